@@ -0,0 +1,261 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Field-level comparison between a container parsed from a manifest and the one
+//! already known to Kanto-CM.
+//!
+//! Only the fields a manifest owns are compared (image, env, cmd, mounts and host_config), with
+//! the values as written, template defaults included. Everything else (id, state, timestamps, ...)
+//! is managed by Kanto-CM itself and would always differ, and so do the few values Kanto-CM fills
+//! in when the manifest leaves them empty (see `FILLED_BY_KANTO`).
+use crate::kanto_cnt::Container;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Display;
+
+/// Values Kanto-CM fills in when they are left empty, with the items of arrays as `[]`
+const FILLED_BY_KANTO: &[&str] = &[
+    "mounts[].propagation_mode",
+    "host_config.devices[].cgroup_permissions",
+    "host_config.port_mappings[].proto",
+    "host_config.port_mappings[].host_ip",
+    "host_config.port_mappings[].host_port_end",
+    "host_config.log_config.driver_config.root_dir",
+    "host_config.log_config.mode_config.max_buffer_size",
+    "host_config.resources",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub path: String,
+    pub existing: Value,
+    pub new: Value,
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.path, self.existing, self.new)
+    }
+}
+
+/// Whether Kanto-CM picks the value at this path, as the manifest leaves it empty
+fn filled_by_kanto(path: &str, new: &Value) -> bool {
+    let empty = match new {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        Value::Number(n) => n.as_f64() == Some(0.0),
+        _ => false,
+    };
+    if !empty {
+        return false;
+    }
+    let mut pattern = String::with_capacity(path.len());
+    let mut in_index = false;
+    for c in path.chars() {
+        match c {
+            '[' => {
+                in_index = true;
+                pattern.push_str("[]");
+            }
+            ']' => in_index = false,
+            _ if in_index => {}
+            _ => pattern.push(c),
+        }
+    }
+    FILLED_BY_KANTO.contains(&pattern.as_str())
+}
+
+/// Walks both values and records every leaf that differs. Arrays of the same length are
+/// compared item by item, so that the values Kanto-CM fills into list items are skipped,
+/// otherwise they are compared as a whole.
+fn diff_values(path: &str, new: &Value, existing: &Value, diffs: &mut Vec<FieldDiff>) {
+    match (new, existing) {
+        (Value::Object(new_map), Value::Object(existing_map)) => {
+            let removed_keys = existing_map.keys().filter(|k| !new_map.contains_key(*k));
+            for key in new_map.keys().chain(removed_keys) {
+                diff_values(
+                    &format!("{}.{}", path, key),
+                    new_map.get(key).unwrap_or(&Value::Null),
+                    existing_map.get(key).unwrap_or(&Value::Null),
+                    diffs,
+                );
+            }
+        }
+        (Value::Array(new_items), Value::Array(existing_items))
+            if new_items.len() == existing_items.len() =>
+        {
+            for (i, (new_item, existing_item)) in new_items.iter().zip(existing_items).enumerate() {
+                diff_values(&format!("{}[{}]", path, i), new_item, existing_item, diffs);
+            }
+        }
+        _ if new != existing && !filled_by_kanto(path, new) => diffs.push(FieldDiff {
+            path: String::from(path),
+            existing: existing.clone(),
+            new: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    // Serializing the generated protobuf types can not fail (no maps with non-string keys)
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+fn diff_field<T: Serialize>(path: &str, new: &T, existing: &T, diffs: &mut Vec<FieldDiff>) {
+    diff_values(path, &to_value(new), &to_value(existing), diffs);
+}
+
+/// Returns the list of manifest-owned fields that differ between the two containers.
+/// An empty list means recreating the container would not change anything.
+pub fn diff_containers(new: &Container, existing: &Container) -> Vec<FieldDiff> {
+    let mut diffs = Vec::new();
+    let new_config = new.config.clone().unwrap_or_default();
+    let existing_config = existing.config.clone().unwrap_or_default();
+
+    diff_field("image", &new.image, &existing.image, &mut diffs);
+    diff_field(
+        "config.env",
+        &new_config.env,
+        &existing_config.env,
+        &mut diffs,
+    );
+    diff_field(
+        "config.cmd",
+        &new_config.cmd,
+        &existing_config.cmd,
+        &mut diffs,
+    );
+    diff_field("mounts", &new.mounts, &existing.mounts, &mut diffs);
+    diff_field(
+        "host_config",
+        &new.host_config,
+        &existing.host_config,
+        &mut diffs,
+    );
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest_parser;
+    use serde_json::json;
+
+    /// A container as parsed from a manifest with the given fields
+    fn container(manifest: Value) -> Container {
+        let expanded = manifest_parser::expand_container_manifest(&manifest).unwrap();
+        serde_json::from_value(expanded).unwrap()
+    }
+
+    fn paths(diffs: &[FieldDiff]) -> Vec<&str> {
+        diffs.iter().map(|d| d.path.as_str()).collect()
+    }
+
+    #[test]
+    fn same_container_has_no_diff() {
+        let c = container(json!({"name": "a", "image": {"name": "img:1"}}));
+        assert!(diff_containers(&c, &c).is_empty());
+    }
+
+    #[test]
+    fn defaults_filled_in_by_kanto_are_ignored() {
+        let new = container(json!({
+            "name": "a",
+            "image": {"name": "img:1"},
+            "mounts": [{"source": "/data", "destination": "/data", "propagation_mode": ""}]
+        }));
+        let existing = container(json!({
+            "name": "a",
+            "id": "1234",
+            "image": {"name": "img:1"},
+            "mounts": [{"source": "/data", "destination": "/data", "propagation_mode": "rprivate"}],
+            "host_config": {
+                "log_config": {"driver_config": {"root_dir": "/var/lib/container-management"}},
+                "resources": {"memory": "", "memory_reservation": "", "memory_swap": ""}
+            },
+            "state": {"running": true, "status": "Running"}
+        }));
+        assert!(diff_containers(&new, &existing).is_empty());
+    }
+
+    #[test]
+    fn values_set_by_the_manifest_are_compared() {
+        let new = container(json!({
+            "name": "a",
+            "image": {"name": "img:2"},
+            "config": {"env": ["A=1"], "cmd": []},
+            "mounts": [{"source": "/new", "destination": "/data", "propagation_mode": ""}],
+            "host_config": {"privileged": true, "restart_policy": {"type": "no"}}
+        }));
+        let existing = container(json!({
+            "name": "a",
+            "image": {"name": "img:1"},
+            "config": {"env": [], "cmd": []},
+            "mounts": [{"source": "/old", "destination": "/data", "propagation_mode": "rprivate"}]
+        }));
+        assert_eq!(
+            paths(&diff_containers(&new, &existing)),
+            vec![
+                "image.name",
+                "config.env",
+                "mounts[0].source",
+                "host_config.privileged",
+                "host_config.restart_policy.type",
+            ]
+        );
+    }
+
+    #[test]
+    fn added_mounts_are_compared_as_a_whole() {
+        let new = container(json!({
+            "name": "a",
+            "image": {"name": "img:1"},
+            "mounts": [{"source": "/data", "destination": "/data", "propagation_mode": ""}]
+        }));
+        let existing = container(json!({"name": "a", "image": {"name": "img:1"}}));
+        assert_eq!(paths(&diff_containers(&new, &existing)), vec!["mounts"]);
+    }
+
+    #[test]
+    fn reverts_to_the_defaults_are_compared() {
+        let new = container(json!({"name": "a", "image": {"name": "img:1"}}));
+        let existing = container(json!({
+            "name": "a",
+            "image": {"name": "img:1"},
+            "mounts": [{"source": "/data", "destination": "/data", "propagation_mode": "rprivate"}],
+            "host_config": {
+                "privileged": true,
+                "network_mode": "host",
+                "restart_policy": {"type": "always"},
+                "port_mappings": [{
+                    "proto": "tcp",
+                    "container_port": 80,
+                    "host_ip": "0.0.0.0",
+                    "host_port": 8080,
+                    "host_port_end": 8080
+                }]
+            }
+        }));
+        assert_eq!(
+            paths(&diff_containers(&new, &existing)),
+            vec![
+                "mounts",
+                "host_config.network_mode",
+                "host_config.port_mappings",
+                "host_config.privileged",
+                "host_config.restart_policy.type",
+            ]
+        );
+    }
+}
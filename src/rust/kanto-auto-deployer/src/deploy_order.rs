@@ -0,0 +1,195 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Orders manifests so that every container is deployed after the containers it depends on.
+//!
+//! Nodes are identified by their index and described by a (container name, dependencies) pair.
+//! Dependencies on names that are not part of the graph are ignored here, as those containers
+//! are not managed by the manifests being deployed. A name used by more than one node is
+//! ambiguous, all of those nodes are reported as duplicates.
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    New,
+    InProgress,
+    Done,
+}
+
+#[derive(Debug, Default)]
+pub struct DeployOrder {
+    /// All node indices, every node placed after its dependencies (unless they form a cycle)
+    pub order: Vec<usize>,
+    /// Nodes that are part of a dependency cycle, with a printable form of the cycle
+    pub cycles: HashMap<usize, String>,
+    /// Nodes whose container name is used by another node as well
+    pub duplicates: HashSet<usize>,
+}
+
+struct Graph<'a> {
+    nodes: &'a [(&'a str, &'a [String])],
+    index: HashMap<&'a str, usize>,
+    marks: Vec<Mark>,
+    stack: Vec<usize>,
+    result: DeployOrder,
+}
+
+impl<'a> Graph<'a> {
+    /// Depth-first visit that appends a node only after all of its dependencies.
+    /// Reaching a node that is still in progress means the path back to it is a cycle.
+    fn visit(&mut self, node: usize) {
+        self.marks[node] = Mark::InProgress;
+        self.stack.push(node);
+        for dependency in self.nodes[node].1 {
+            let dep_idx = match self.index.get(dependency.as_str()) {
+                Some(i) => *i,
+                None => continue,
+            };
+            match self.marks[dep_idx] {
+                Mark::New => self.visit(dep_idx),
+                Mark::InProgress => self.record_cycle(dep_idx),
+                Mark::Done => {}
+            }
+        }
+        self.stack.pop();
+        self.marks[node] = Mark::Done;
+        self.result.order.push(node);
+    }
+
+    fn record_cycle(&mut self, cycle_start: usize) {
+        let start = self
+            .stack
+            .iter()
+            .position(|n| *n == cycle_start)
+            .unwrap_or_default();
+        let cycle_nodes = &self.stack[start..];
+        let description = cycle_nodes
+            .iter()
+            .chain(std::iter::once(&cycle_start))
+            .map(|n| self.nodes[*n].0)
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        for node in cycle_nodes {
+            self.result
+                .cycles
+                .entry(*node)
+                .or_insert_with(|| description.clone());
+        }
+    }
+}
+
+/// Computes the deployment order for the given (container name, dependencies) nodes.
+/// Nodes with an empty name can not be depended upon.
+pub fn resolve(nodes: &[(&str, &[String])]) -> DeployOrder {
+    let mut index = HashMap::new();
+    let mut result = DeployOrder::default();
+    for (i, (name, _)) in nodes.iter().enumerate() {
+        if name.is_empty() {
+            continue;
+        }
+        if let Some(first) = index.insert(*name, i) {
+            result.duplicates.insert(first);
+            result.duplicates.insert(i);
+        }
+    }
+    let mut graph = Graph {
+        nodes,
+        index,
+        marks: vec![Mark::New; nodes.len()],
+        stack: Vec::new(),
+        result,
+    };
+    for node in 0..nodes.len() {
+        if graph.marks[node] == Mark::New {
+            graph.visit(node);
+        }
+    }
+    graph.result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    fn position(order: &DeployOrder, node: usize) -> usize {
+        order.order.iter().position(|n| *n == node).unwrap()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let (db, cache, app) = (deps(&[]), deps(&["db"]), deps(&["cache", "db", "external"]));
+        let nodes = [
+            ("app", app.as_slice()),
+            ("cache", cache.as_slice()),
+            ("db", db.as_slice()),
+        ];
+        let order = resolve(&nodes);
+        assert_eq!(order.order, vec![2, 1, 0]);
+        assert!(order.cycles.is_empty());
+        assert!(order.duplicates.is_empty());
+    }
+
+    #[test]
+    fn independent_nodes_keep_their_order() {
+        let none = deps(&[]);
+        let nodes = [
+            ("a", none.as_slice()),
+            ("", none.as_slice()),
+            ("b", none.as_slice()),
+        ];
+        assert_eq!(resolve(&nodes).order, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let (a, b, c, d) = (deps(&["b"]), deps(&["c"]), deps(&["a"]), deps(&["a"]));
+        let nodes = [
+            ("a", a.as_slice()),
+            ("b", b.as_slice()),
+            ("c", c.as_slice()),
+            ("d", d.as_slice()),
+        ];
+        let order = resolve(&nodes);
+        assert_eq!(order.order.len(), 4);
+        assert!(position(&order, 0) < position(&order, 3));
+        assert_eq!(order.cycles.len(), 3);
+        assert_eq!(order.cycles[&0], "a -> b -> c -> a");
+        assert!(!order.cycles.contains_key(&3));
+    }
+
+    #[test]
+    fn self_dependency_is_a_cycle() {
+        let a = deps(&["a"]);
+        let order = resolve(&[("a", a.as_slice())]);
+        assert_eq!(order.cycles[&0], "a -> a");
+    }
+
+    #[test]
+    fn duplicate_names_are_reported() {
+        let none = deps(&[]);
+        let nodes = [
+            ("a", none.as_slice()),
+            ("b", none.as_slice()),
+            ("a", none.as_slice()),
+            ("", none.as_slice()),
+            ("", none.as_slice()),
+        ];
+        let order = resolve(&nodes);
+        assert_eq!(order.duplicates, HashSet::from([0, 2]));
+        assert_eq!(order.order.len(), 5);
+    }
+}
@@ -0,0 +1,114 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Decides what should happen to a container described by a manifest.
+//!
+//! The same decision is used when actually deploying and when running in dry-run
+//! mode, so that the printed plan always matches what a real run would do.
+use crate::container_diff;
+use crate::container_running;
+use crate::kanto_cnt::Container;
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    /// No container with that name exists, it would be created and started
+    Create,
+    /// The container exists but differs from the manifest, it would be stopped, removed and created anew
+    Recreate,
+    /// The container exists but is not running, it would only be started
+    Start,
+    /// The container exists and is running, nothing would be done
+    Unchanged,
+    /// The container exists but was not created by the auto-deployer, it would be left as is
+    Skip,
+    /// The manifest could not be read or parsed
+    Invalid,
+    /// The container was created by the auto-deployer but no manifest describes it anymore,
+    /// it would be stopped and removed
+    Prune,
+}
+
+impl Display for PlanAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str_repr = match self {
+            PlanAction::Create => "create",
+            PlanAction::Recreate => "recreate",
+            PlanAction::Start => "start",
+            PlanAction::Unchanged => "leave unchanged",
+            PlanAction::Skip => "skip",
+            PlanAction::Invalid => "invalid",
+            PlanAction::Prune => "prune",
+        };
+        write!(f, "{}", str_repr)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlanEntry {
+    pub manifest: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    pub action: PlanAction,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Picks the action for a manifest given the container with the same name (if any).
+/// Even when `recreate` is set, a container is only recreated if the manifest-owned fields
+/// actually differ, so that touching or rewriting a manifest does not restart the workload.
+pub fn plan_action(new: &Container, existing: Option<&Container>, recreate: bool) -> PlanAction {
+    match existing {
+        None => PlanAction::Create,
+        Some(c) if recreate && !container_diff::diff_containers(new, c).is_empty() => {
+            PlanAction::Recreate
+        }
+        Some(c) if !container_running(c) => PlanAction::Start,
+        Some(_) => PlanAction::Unchanged,
+    }
+}
+
+pub fn print_plan(plan: &[PlanEntry], format: PlanFormat) -> anyhow::Result<()> {
+    match format {
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(plan)?),
+        PlanFormat::Text => {
+            for entry in plan {
+                match (&entry.container, &entry.error) {
+                    (Some(name), _) => {
+                        println!("{} [{}] ({:?})", entry.action, name, entry.manifest);
+                        for change in &entry.changes {
+                            println!("    {}", change);
+                        }
+                        if let Some(e) = &entry.error {
+                            println!("    {}", e);
+                        }
+                    }
+                    (None, Some(e)) => println!("{} {:?}: {}", entry.action, entry.manifest, e),
+                    (None, None) => println!("{} {:?}", entry.action, entry.manifest),
+                }
+            }
+        }
+    }
+    Ok(())
+}
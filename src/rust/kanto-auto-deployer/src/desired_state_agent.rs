@@ -0,0 +1,420 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! A minimal update agent for the "containers" domain of VUM desired states.
+//!
+//! On devices without the update agent stack, the desired state messages (e.g. published by
+//! blueprint-selector) are applied directly: every component is created or recreated as needed,
+//! and the containers of components dropped from the desired state are removed. The outcome is
+//! published as desired state feedback in the VUM envelope.
+use crate::deploy_plan::PlanAction;
+use crate::manifest_parser::desired_state::{self, CONTAINERS_DOMAIN};
+use crate::manifest_parser::Manifest;
+use crate::mqtt_connection;
+use crate::mqtt_listener::VUMEnvelope;
+use crate::mqtt_publisher::timestamp_millis;
+use crate::signals;
+use crate::status_report::{self, LastAction};
+use crate::{deploy_manifests, deployments_paused, undeploy, CliArgs, DeploySettings};
+use anyhow::{anyhow, Result};
+use rumqttc::{Client, Event::Incoming, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static CLIENT_ID_SUFFIX: &str = "_agent";
+static RECONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+static CHECK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Written next to the status file
+static STATE_FILE: &str = "desired-state.json";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum FeedbackStatus {
+    Identified,
+    IdentificationFailed,
+    Completed,
+    Incomplete,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ActionStatus {
+    Identified,
+    UpdateSuccess,
+    UpdateFailure,
+    RemovalSuccess,
+    RemovalFailure,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Component {
+    id: String,
+    version: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Action {
+    component: Component,
+    status: ActionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Feedback {
+    status: FeedbackStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    actions: Vec<Action>,
+}
+
+/// The components applied last, so that their containers can be removed once they are dropped
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AppliedState {
+    activity_id: String,
+    /// Version of every component, by container name
+    components: BTreeMap<String, String>,
+}
+
+fn state_file(status_file: &Path) -> PathBuf {
+    status_file.with_file_name(STATE_FILE)
+}
+
+fn load_state(path: &Path) -> AppliedState {
+    let state = std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_json::from_str(&content)?));
+    match state {
+        Ok(s) => s,
+        Err(e) => {
+            if path.exists() {
+                log::warn!("Ignoring unreadable desired state file {:?}: {}", path, e);
+            }
+            AppliedState::default()
+        }
+    }
+}
+
+fn component(name: &str, version: &str) -> Component {
+    Component {
+        id: format!("{}:{}", CONTAINERS_DOMAIN, name),
+        version: String::from(version),
+    }
+}
+
+struct Agent {
+    cli: Arc<CliArgs>,
+    settings: DeploySettings,
+    client: Mutex<Client>,
+    feedback_topic: String,
+    state_path: PathBuf,
+}
+
+impl Agent {
+    fn feedback(&self, activity_id: &str, feedback: Feedback) {
+        let envelope = VUMEnvelope {
+            activity_id: String::from(activity_id),
+            timestamp: timestamp_millis(),
+            payload: feedback,
+        };
+        let published = serde_json::to_vec(&envelope)
+            .map_err(anyhow::Error::from)
+            .and_then(|payload| {
+                self.client
+                    .lock()
+                    .unwrap()
+                    .try_publish(&self.feedback_topic, QoS::AtLeastOnce, false, payload)
+                    .map_err(anyhow::Error::from)
+            });
+        if let Err(e) = published {
+            log::warn!("Could not publish desired state feedback: {e}");
+        }
+    }
+
+    fn identification_failed(&self, activity_id: &str, e: impl std::fmt::Display) {
+        log::error!("Invalid desired state (activity {}): {}", activity_id, e);
+        self.feedback(
+            activity_id,
+            Feedback {
+                status: FeedbackStatus::IdentificationFailed,
+                message: Some(e.to_string()),
+                actions: Vec::new(),
+            },
+        );
+    }
+
+    async fn apply(&self, activity_id: &str, desired_state: &Value) {
+        // Next to the update agent that took over, nothing is changed until KAD is enabled again
+        if deployments_paused(&self.cli) {
+            log::warn!(
+                "Not applying desired state (activity {}), the auto-deployer is disabled",
+                activity_id
+            );
+            return self.feedback(
+                activity_id,
+                Feedback {
+                    status: FeedbackStatus::IdentificationFailed,
+                    message: Some(String::from(
+                        "The auto-deployer is disabled, as an update agent took over",
+                    )),
+                    actions: Vec::new(),
+                },
+            );
+        }
+        let desired = match desired_state::convert(desired_state) {
+            Ok(d) => d,
+            Err(e) => return self.identification_failed(activity_id, e),
+        };
+        for warning in &desired.conversion.warnings {
+            log::warn!("[{}] {}", desired.conversion.name, warning);
+        }
+        let manifests = desired
+            .conversion
+            .containers
+            .into_iter()
+            .map(|converted| {
+                Ok(Manifest {
+                    container: serde_json::from_value(converted.container)?,
+                    options: Default::default(),
+                })
+            })
+            .collect::<Result<Vec<Manifest>>>();
+        let manifests = match manifests {
+            Ok(m) => m,
+            Err(e) => return self.identification_failed(activity_id, e),
+        };
+
+        let previous = load_state(&self.state_path);
+        let dropped: Vec<(String, String)> = previous
+            .components
+            .into_iter()
+            .filter(|(name, _)| !desired.versions.contains_key(name))
+            .collect();
+        let names: Vec<String> = manifests.iter().map(|m| m.container.name.clone()).collect();
+        let version = |name: &str| desired.versions.get(name).cloned().unwrap_or_default();
+
+        let identified = names
+            .iter()
+            .map(|name| (name.as_str(), version(name)))
+            .chain(dropped.iter().map(|(n, v)| (n.as_str(), v.clone())))
+            .map(|(name, version)| Action {
+                component: component(name, &version),
+                status: ActionStatus::Identified,
+                message: None,
+            })
+            .collect();
+        self.feedback(
+            activity_id,
+            Feedback {
+                status: FeedbackStatus::Identified,
+                message: None,
+                actions: identified,
+            },
+        );
+
+        // Not while the daemon deploys the manifests directory
+        let _deploying = self.settings.deploying.lock().await;
+        log::info!(
+            "Applying desired state (activity {}): {} container(s), removing {}",
+            activity_id,
+            names.len(),
+            dropped.len()
+        );
+        let deployments = deploy_manifests(
+            &self.settings,
+            manifests
+                .into_iter()
+                .map(|m| Ok((self.state_path.clone(), m)))
+                .collect(),
+            true,
+        )
+        .await;
+        let mut actions = Vec::new();
+        for (name, deployment) in names.iter().zip(deployments) {
+            let (status, message) = match deployment {
+                // Left as is, so the desired state is not in place
+                Ok(d) if d.action == PlanAction::Skip => (
+                    ActionStatus::UpdateFailure,
+                    format!(
+                        "[{}] was not created by the auto-deployer, leaving it as is",
+                        d.name
+                    ),
+                ),
+                Ok(d) => {
+                    // Owned as part of the applied desired state
+                    self.settings.ownership.record(
+                        &d.name,
+                        &d.id,
+                        &self.state_path,
+                        None,
+                        d.stop.clone(),
+                    );
+                    (
+                        ActionStatus::UpdateSuccess,
+                        LastAction::from(d.action).to_string(),
+                    )
+                }
+                Err(e) => {
+                    log::error!("[CM error] {:?}", e.root_cause());
+                    (ActionStatus::UpdateFailure, format!("{:#}", e))
+                }
+            };
+            actions.push(Action {
+                component: component(name, &version(name)),
+                status,
+                message: Some(message),
+            });
+        }
+
+        let mut applied = AppliedState {
+            activity_id: String::from(activity_id),
+            components: desired.versions.into_iter().collect(),
+        };
+        for (name, version) in dropped {
+            log::info!("[{}] is no longer in the desired state, removing it", name);
+            let (status, message) = match undeploy(&self.settings, &name).await {
+                Ok(()) => (ActionStatus::RemovalSuccess, None),
+                Err(e) => {
+                    log::error!("[CM error] {:?}", e.root_cause());
+                    // Try again with the next desired state
+                    applied.components.insert(name.clone(), version.clone());
+                    (ActionStatus::RemovalFailure, Some(format!("{:#}", e)))
+                }
+            };
+            actions.push(Action {
+                component: component(&name, &version),
+                status,
+                message,
+            });
+        }
+        let saved = serde_json::to_string_pretty(&applied)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| status_report::write_atomically(&self.state_path, &content));
+        if let Err(e) = saved {
+            log::warn!(
+                "Could not write desired state file {:?}: {}",
+                self.state_path,
+                e
+            );
+        }
+
+        let failed = actions
+            .iter()
+            .filter(|a| {
+                matches!(
+                    a.status,
+                    ActionStatus::UpdateFailure | ActionStatus::RemovalFailure
+                )
+            })
+            .count();
+        let (status, message) = if failed == 0 {
+            (FeedbackStatus::Completed, None)
+        } else {
+            log::error!(
+                "Desired state (activity {}) applied with {} failure(s)",
+                activity_id,
+                failed
+            );
+            let message = format!("{} of {} action(s) failed", failed, actions.len());
+            (FeedbackStatus::Incomplete, Some(message))
+        };
+        self.feedback(
+            activity_id,
+            Feedback {
+                status,
+                message,
+                actions,
+            },
+        );
+    }
+}
+
+/// Applies every desired state published on the configured topic. Only returns if the
+/// connection can not be set up.
+pub fn agent_main(cli: Arc<CliArgs>, settings: DeploySettings) -> Result<()> {
+    let config = &cli.mqtt;
+    let state_path = state_file(&cli.status_file);
+    let mqttoptions = mqtt_connection::mqtt_options(config, CLIENT_ID_SUFFIX)?;
+    let (client, mut connection) = Client::new(mqttoptions, 10);
+    let topic = config.desired_state_topic.clone();
+    let (messages_tx, messages_rx) = mpsc::channel();
+
+    // Desired states are applied one after the other, while the connection is kept alive here
+    thread::spawn({
+        let mut client = client.clone();
+        let topic = topic.clone();
+        move || {
+            for notification in connection.iter() {
+                match notification {
+                    // The subscription does not survive a reconnect, so (re-)subscribe every time
+                    Ok(Incoming(Packet::ConnAck(_))) => {
+                        if let Err(e) = client.try_subscribe(&topic, QoS::ExactlyOnce) {
+                            log::error!("Could not subscribe to {:?}: {}", topic, e);
+                        }
+                    }
+                    Ok(Incoming(Packet::Publish(msg))) if msg.topic == topic => {
+                        if messages_tx.send(msg.payload).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) if signals::shutting_down() => break,
+                    Err(e) => {
+                        log::error!(
+                            "MQTT desired state connection error: {}",
+                            mqtt_connection::describe_error(&e)
+                        );
+                        thread::sleep(RECONNECT_TIMEOUT);
+                    }
+                }
+            }
+        }
+    });
+
+    // The deployment futures are not Send, so they are driven on this thread
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| anyhow!("Could not start the desired state runtime: {}", e))?;
+    let agent = Agent {
+        cli: cli.clone(),
+        settings,
+        client: Mutex::new(client),
+        feedback_topic: config.desired_state_feedback_topic.clone(),
+        state_path,
+    };
+    log::info!("Applying the desired states published on {:?}", topic);
+    loop {
+        // The desired state being applied is completed (and its feedback sent) before shutting down
+        if signals::shutting_down() {
+            if let Err(e) = agent.client.lock().unwrap().try_disconnect() {
+                log::debug!("Could not disconnect the desired state agent: {e}");
+            }
+            return Ok(());
+        }
+        let payload = match messages_rx.recv_timeout(CHECK_SHUTDOWN_TIMEOUT) {
+            Ok(payload) => payload,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        match serde_json::from_slice::<VUMEnvelope<Value>>(&payload) {
+            Ok(msg) => runtime.block_on(agent.apply(&msg.activity_id, &msg.payload)),
+            Err(e) => log::warn!("Ignoring invalid desired state message: {e}"),
+        }
+    }
+}
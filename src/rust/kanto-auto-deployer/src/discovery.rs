@@ -0,0 +1,100 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Discovery of the manifests in the manifests directory and all of its subdirectories.
+//!
+//! The initial deployment and the filesystem watcher share the same `ManifestFilter`,
+//! so a manifest is either picked-up by both or by neither of them. Hidden files and directories
+//! (`.git`, editor backups, ...) are skipped.
+use crate::manifest_parser::ManifestSyntax;
+use anyhow::{anyhow, Result};
+use glob::{glob_with, MatchOptions, Pattern};
+use std::path::{Path, PathBuf};
+
+/// `*` and `?` do not match across directories, `**` does. Neither matches a leading dot
+static MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+fn is_hidden(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestFilter {
+    root: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).map_err(|e| anyhow!("Invalid glob pattern \"{}\": {}", p, e)))
+        .collect()
+}
+
+impl ManifestFilter {
+    /// The patterns are matched against the manifest paths relative to the root.
+    /// Without include patterns, every manifest with a supported file extension is included.
+    pub fn new(root: &Path, include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(ManifestFilter {
+            root: root.to_path_buf(),
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        if ManifestSyntax::from_path(path).is_none() {
+            return false;
+        }
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(r) => r,
+            Err(_) => return false,
+        };
+        if is_hidden(relative) {
+            return false;
+        }
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|p| p.matches_path_with(relative, MATCH_OPTIONS));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|p| p.matches_path_with(relative, MATCH_OPTIONS))
+    }
+
+    /// Finds the matching manifests in the directory (the root or any directory below it)
+    /// and all of its subdirectories, sorted by path.
+    pub fn find(&self, directory: &Path) -> Result<Vec<PathBuf>> {
+        let all_files = format!("{}/**/*", Pattern::escape(&directory.to_string_lossy()));
+        let mut found: Vec<PathBuf> = glob_with(&all_files, MATCH_OPTIONS)?
+            .filter_map(Result::ok)
+            .filter(|p| p.is_file() && self.matches(p))
+            .collect();
+        found.sort();
+        Ok(found)
+    }
+}
@@ -11,56 +11,165 @@
 // * SPDX-License-Identifier: Apache-2.0
 // ********************************************************************************
 
-use notify::{Config, PollWatcher, RecursiveMode, Watcher};
+use crate::signals::{DaemonSignal, Signals};
+use crate::systemd;
+use notify::event::{ModifyKind, RemoveKind};
+use notify::{Config, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
 use std::future::Future;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{path::Path, time::Duration};
 
 pub use notify::Event;
 use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::{interval, interval_at, sleep_until, Instant, MissedTickBehavior};
 use tokio::{select, time::sleep};
 
-const POLL_SECONDS: f64 = 10.0;
+pub const POLL_SECONDS: f64 = 10.0;
 static CHECK_TERMINATION_FLAG_TIMEOUT: Duration = Duration::from_secs(2);
 
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum WatchBackend {
+    /// Native filesystem notifications (inotify on Linux)
+    Inotify,
+    /// Periodically compare the contents of the watched files
+    Poll,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchConfig {
+    pub backend: WatchBackend,
+    /// Changes to the same path within this window are reported only once
+    pub debounce: Duration,
+    /// Interval between scans when using the polling backend
+    pub poll_interval: Duration,
+    /// Interval between two reconciliations, if enabled
+    pub reconcile_interval: Option<Duration>,
+    /// Interval between the pings of the systemd watchdog, if it is enabled
+    pub watchdog: Option<Duration>,
+}
+
+type EventReceiver = Receiver<notify::Result<Event>>;
+
+pub enum WatchEvent {
+    /// The path changed, after the debounce window
+    Changed(Event),
+    /// The reconcile interval passed
+    Reconcile,
+}
+
+/// Why the watch was stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchExit {
+    /// The termination flag was set (VUM took over)
+    Terminated,
+    Signal(DaemonSignal),
+}
+
 /// Based on the examples from the notify crate for async watchers
 /// Here template callbacks are used and the async runtime was changed to
 /// tokio as this is the one used by KAD anyway.
-fn async_watcher() -> notify::Result<(PollWatcher, Receiver<notify::Result<Event>>)> {
+fn async_watcher<W: Watcher>(config: Config) -> notify::Result<(W, EventReceiver)> {
     let (tx, rx) = channel(1);
 
-    let config = Config::default()
-        .with_poll_interval(Duration::from_secs_f64(POLL_SECONDS))
-        .with_compare_contents(true);
-
-    let rt = tokio::runtime::Runtime::new().unwrap();
-
-    let watcher = PollWatcher::new(
+    // The callback runs on the thread of the watcher, outside of the async runtime.
+    // Events arriving after the watch was stopped are dropped.
+    let watcher = W::new(
         move |res| {
-            rt.block_on(async {
-                tx.send(res).await.unwrap();
-            })
+            let _ = tx.blocking_send(res);
         },
         config,
     )?;
     Ok((watcher, rx))
 }
 
+fn poll_watcher(
+    path: &Path,
+    config: &WatchConfig,
+) -> notify::Result<(Box<dyn Watcher>, EventReceiver)> {
+    let poll_config = Config::default()
+        .with_poll_interval(config.poll_interval)
+        .with_compare_contents(true);
+    let (mut watcher, rx) = async_watcher::<PollWatcher>(poll_config)?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok((Box::new(watcher), rx))
+}
+
+fn inotify_watcher(path: &Path) -> notify::Result<(Box<dyn Watcher>, EventReceiver)> {
+    let (mut watcher, rx) = async_watcher::<RecommendedWatcher>(Config::default())?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok((Box::new(watcher), rx))
+}
+
+/// Sets up the requested backend. Native notifications may be unavailable
+/// (e.g. on some network or overlay filesystems, or when the inotify watch limit is reached),
+/// in which case polling is used instead.
+fn start_watcher(
+    path: &Path,
+    config: &WatchConfig,
+) -> notify::Result<(Box<dyn Watcher>, EventReceiver)> {
+    if config.backend == WatchBackend::Inotify {
+        match inotify_watcher(path) {
+            Ok(w) => return Ok(w),
+            Err(e) => log::warn!(
+                "Could not watch {:?} with inotify ({}), falling back to polling every {} s",
+                path,
+                e,
+                config.poll_interval.as_secs_f64()
+            ),
+        }
+    }
+    poll_watcher(path, config)
+}
+
+/// Only changes to the contents or presence of a file are of interest (no access events)
+fn is_relevant(kind: &EventKind) -> bool {
+    kind.is_create() || kind.is_modify() || kind.is_remove()
+}
+
+/// A burst of events for the same path (e.g. create + modify + close from an editor save)
+/// collapses into one event that only tells whether the path still exists.
+fn settled_event(path: PathBuf) -> Event {
+    let kind = if path.exists() {
+        EventKind::Modify(ModifyKind::Any)
+    } else {
+        EventKind::Remove(RemoveKind::Any)
+    };
+    Event::new(kind).add_path(path)
+}
+
+/// Calls back for every settled change (and reconcile interval) until the termination flag
+/// is set or a signal is received. A change that is being handled is always completed first.
 pub async fn async_watch<'a, P, F, Fut>(
     thread_terminate_flag: &AtomicBool,
+    signals: &mut Signals,
     path: P,
+    config: WatchConfig,
     callback: F,
-) -> notify::Result<()>
+) -> notify::Result<WatchExit>
 where
     P: AsRef<Path>,
-    F: Fn(Event) -> Fut,
+    F: Fn(WatchEvent) -> Fut,
     Fut: Future<Output = ()>,
 {
-    let (mut watcher, mut rx) = async_watcher()?;
+    // The watcher stops as soon as it is dropped, keep it around until the loop is over
+    let (_watcher, mut rx) = start_watcher(path.as_ref(), &config)?;
 
-    watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
+    // Changed paths waiting for the debounce window to pass, with the time they settle at
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+
+    // Pinged from this loop only, so that the watchdog fires if a callback hangs
+    let mut watchdog = config.watchdog.map(interval);
+    let mut reconcile = config.reconcile_interval.map(|period| {
+        let mut reconcile = interval_at(Instant::now() + period, period);
+        // A slow reconciliation is not followed by another one right away
+        reconcile.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        reconcile
+    });
 
     loop {
+        let next_settled = pending.values().min().copied();
         // Do not check the termination flag all the time (= busy wait)
         // If a redeploy event completes before the flag check timeout
         // is over, handle the filesystem event (select handles the first future
@@ -69,25 +178,42 @@ where
             _ = sleep(CHECK_TERMINATION_FLAG_TIMEOUT) => {
                 if thread_terminate_flag.load(Ordering::Relaxed) {
                     log::warn!("Getting terminated from MQTT!");
-                    break;
+                    return Ok(WatchExit::Terminated);
+                }
+            }
+            _ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+                systemd::watchdog();
+            }
+            _ = async { reconcile.as_mut().unwrap().tick().await }, if reconcile.is_some() => {
+                callback(WatchEvent::Reconcile).await
+            }
+            signal = signals.recv() => {
+                if !pending.is_empty() && signal == DaemonSignal::Shutdown {
+                    log::info!("Not handling {} pending change(s) in {:?}", pending.len(), path.as_ref());
                 }
+                return Ok(WatchExit::Signal(signal));
             }
             Some(event) = rx.recv() => {
-                callback(event?).await
+                let event = event?;
+                if is_relevant(&event.kind) {
+                    let settles_at = Instant::now() + config.debounce;
+                    for p in event.paths {
+                        pending.insert(p, settles_at);
+                    }
+                }
+            }
+            _ = sleep_until(next_settled.unwrap_or_else(Instant::now)), if next_settled.is_some() => {
+                let now = Instant::now();
+                let settled: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, settles_at)| **settles_at <= now)
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                for p in settled {
+                    pending.remove(&p);
+                    callback(WatchEvent::Changed(settled_event(p))).await
+                }
             }
         }
     }
-    Ok(())
-}
-
-pub fn is_filetype(path: &Path, extension: &str) -> bool {
-    if path.extension().is_none() {
-        return false;
-    }
-
-    if path.extension().unwrap() == extension {
-        return true;
-    }
-
-    false
 }
@@ -0,0 +1,277 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Layered configuration of the auto-deployer.
+//!
+//! Every command line option can also be set with a `KAD_` environment variable named after it
+//! (`--socket-cm` is `KAD_SOCKET_CM`), or in the TOML configuration file under the same name in
+//! snake case (`socket_cm = "..."`). The MQTT options go into the `[mqtt]` table without their
+//! prefix (`--mqtt-broker-host` is `broker_host`, `--mqtt` itself is `enabled`).
+//! The command line takes precedence over the environment, which takes precedence over the file.
+//!
+//! The values from the file are handed to clap as defaults, so they are parsed and validated
+//! exactly like the command line.
+use crate::CliArgs;
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, CommandFactory, FromArgMatches, ValueSource};
+use std::path::Path;
+
+pub static DEFAULT_CONFIG_FILE: &str = "/etc/kanto-auto-deployer/kad.toml";
+pub static CONFIG_FILE_ENV: &str = "KAD_CONFIG";
+static ENV_PREFIX: &str = "KAD_";
+static MQTT_TABLE: &str = "mqtt";
+static MQTT_PREFIX: &str = "mqtt-";
+/// Arguments that can only be given on the command line
+static NOT_CONFIGURABLE: [&str; 3] = ["help", "version", "config"];
+/// Arguments whose values are not shown in the help
+static SECRET: [&str; 1] = ["password"];
+
+/// clap 3 needs static strings, the configuration lives as long as the process anyway
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+fn is_configurable(arg: &Arg) -> bool {
+    !NOT_CONFIGURABLE.contains(&arg.get_id())
+}
+
+/// Name of the option, as on the command line without dashes
+fn option_name<'a>(arg: &Arg<'a>) -> &'a str {
+    arg.get_long().unwrap_or_else(|| arg.get_id())
+}
+
+fn env_name(arg: &Arg) -> String {
+    format!(
+        "{}{}",
+        ENV_PREFIX,
+        option_name(arg).replace('-', "_").to_uppercase()
+    )
+}
+
+/// The table (if any) and key of the option in the configuration file
+fn file_key(arg: &Arg) -> (Option<&'static str>, String) {
+    let name = option_name(arg);
+    if name == MQTT_TABLE {
+        return (Some(MQTT_TABLE), String::from("enabled"));
+    }
+    match name.strip_prefix(MQTT_PREFIX) {
+        Some(mqtt_name) => (Some(MQTT_TABLE), mqtt_name.replace('-', "_")),
+        None => (None, name.replace('-', "_")),
+    }
+}
+
+fn display_key(table: Option<&str>, key: &str) -> String {
+    match table {
+        Some(table) => format!("{}.{}", table, key),
+        None => String::from(key),
+    }
+}
+
+/// The command line parser, reading the environment as well
+fn command() -> clap::Command<'static> {
+    let mut cmd = CliArgs::command();
+    let envs: Vec<(&'static str, String)> = cmd
+        .get_arguments()
+        .filter(|a| is_configurable(a))
+        .map(|a| (a.get_id(), env_name(a)))
+        .collect();
+    for (id, env) in envs {
+        let secret = SECRET.contains(&id);
+        cmd = cmd.mut_arg(id, |a| {
+            a.env(leak(env))
+                .hide_env_values(secret)
+                .hide_default_value(secret)
+        });
+    }
+    cmd
+}
+
+fn toml_values(value: &toml::Value, key: &str, multiple: bool) -> Result<Vec<String>> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Float(f) => Ok(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(items) if multiple => items
+            .iter()
+            .map(|item| match toml_values(item, key, false)?.pop() {
+                Some(v) => Ok(v),
+                None => Err(anyhow!("`{}` must be a list of values", key)),
+            })
+            .collect(),
+        toml::Value::Array(_) => Err(anyhow!("`{}` takes a single value, not a list", key)),
+        _ => Err(anyhow!("`{}` must be a string, number or boolean", key)),
+    }
+}
+
+/// Sets the values in the file as defaults of the command line options
+fn apply_file(mut cmd: clap::Command<'static>, path: &Path) -> Result<clap::Command<'static>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Could not read config file {:?}: {}", path, e))?;
+    let table: toml::Table =
+        toml::from_str(&content).map_err(|e| anyhow!("Invalid config file {:?}: {}", path, e))?;
+
+    let mut settings: Vec<(Option<&str>, &String, &toml::Value)> = Vec::new();
+    for (key, value) in &table {
+        match value {
+            toml::Value::Table(mqtt) if key == MQTT_TABLE => {
+                settings.extend(mqtt.iter().map(|(k, v)| (Some(MQTT_TABLE), k, v)))
+            }
+            _ => settings.push((None, key, value)),
+        }
+    }
+
+    for (table, key, value) in settings {
+        let display_key = display_key(table, key);
+        let arg = cmd
+            .get_arguments()
+            .filter(|a| is_configurable(a))
+            .find(|a| file_key(a) == (table, key.clone()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown setting `{}` in config file {:?}",
+                    display_key,
+                    path
+                )
+            })?;
+        let multiple = arg.is_multiple_occurrences_set() || arg.is_multiple_values_set();
+        let id = arg.get_id();
+        let values: Vec<&'static str> = toml_values(value, &display_key, multiple)
+            .map_err(|e| anyhow!("In config file {:?}: {}", path, e))?
+            .into_iter()
+            .map(leak)
+            .collect();
+        cmd = cmd.mut_arg(id, |a| a.default_values(&values));
+    }
+    Ok(cmd)
+}
+
+fn from_matches(matches: &ArgMatches) -> Result<CliArgs> {
+    Ok(CliArgs::from_arg_matches(matches)?)
+}
+
+/// Parses the command line, the environment and the configuration file (if it exists).
+/// Exits on invalid command line arguments, like clap does.
+pub fn parse() -> Result<CliArgs> {
+    parse_with(|cmd| Ok(cmd.get_matches()))
+}
+
+/// Parses everything again, to pick up the changes to the configuration file.
+/// Unlike [`parse`], invalid values are returned as errors.
+#[cfg(feature = "filewatcher")]
+pub fn reload() -> Result<CliArgs> {
+    parse_with(|cmd| Ok(cmd.try_get_matches()?))
+}
+
+fn parse_with(
+    get_matches: impl Fn(clap::Command<'static>) -> Result<ArgMatches>,
+) -> Result<CliArgs> {
+    // The first pass only finds the configuration file
+    let matches = get_matches(command())?;
+    let config_file = from_matches(&matches)?.config;
+    let explicit = matches.value_source("config") != Some(ValueSource::DefaultValue);
+    if !explicit && !config_file.exists() {
+        return from_matches(&matches);
+    }
+    let cmd = apply_file(command(), &config_file)?;
+    from_matches(&get_matches(cmd)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Every test uses other options, as the environment is shared by the tests running in parallel
+    fn config_file(test: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kad-config-{}-{}.toml", std::process::id(), test));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn parse_from(args: &[&str]) -> Result<CliArgs> {
+        let args: Vec<&str> = std::iter::once("kanto-auto-deployer")
+            .chain(args.iter().copied())
+            .collect();
+        parse_with(|cmd| Ok(cmd.try_get_matches_from(&args)?))
+    }
+
+    #[test]
+    fn file_sets_the_defaults() {
+        let file = config_file(
+            "defaults",
+            "socket_cm = \"/run/cm.sock\"\nexclude = [\"a/**\", \"b/**\"]\n",
+        );
+        let cli = parse_from(&["--config", file.to_str().unwrap()]).unwrap();
+        assert_eq!(cli.socket_cm, PathBuf::from("/run/cm.sock"));
+        assert_eq!(cli.exclude, vec!["a/**", "b/**"]);
+        assert_eq!(cli.ready_settle_secs, 5);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let file = config_file("environment", "ready_timeout_secs = 5\n");
+        std::env::set_var("KAD_READY_TIMEOUT_SECS", "7");
+        let cli = parse_from(&["--config", file.to_str().unwrap()]);
+        std::env::remove_var("KAD_READY_TIMEOUT_SECS");
+        assert_eq!(cli.unwrap().ready_timeout_secs, 7);
+    }
+
+    #[test]
+    fn command_line_overrides_environment_and_file() {
+        let file = config_file("command-line", "stop_signal = \"SIGINT\"\n");
+        std::env::set_var("KAD_STOP_SIGNAL", "SIGKILL");
+        let cli = parse_from(&[
+            "--config",
+            file.to_str().unwrap(),
+            "--stop-signal",
+            "SIGUSR1",
+        ]);
+        std::env::remove_var("KAD_STOP_SIGNAL");
+        assert_eq!(cli.unwrap().stop_signal, "SIGUSR1");
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn mqtt_options_are_in_their_table() {
+        let file = config_file("mqtt", "[mqtt]\nenabled = true\nbroker_port = 8883\n");
+        let cli = parse_from(&["--config", file.to_str().unwrap()]).unwrap();
+        assert!(cli.mqtt.enabled);
+        assert_eq!(cli.mqtt.port, 8883);
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let file = config_file("unknown", "socket = \"/run/cm.sock\"\n");
+        let err = parse_from(&["--config", file.to_str().unwrap()]).unwrap_err();
+        assert!(err.to_string().contains("Unknown setting `socket`"));
+    }
+
+    #[test]
+    fn missing_explicit_file_is_an_error() {
+        let missing =
+            std::env::temp_dir().join(format!("kad-config-{}-missing.toml", std::process::id()));
+        let missing = leak(missing.to_string_lossy().into_owned());
+        assert!(parse_from(&["--config", missing]).is_err());
+
+        // The default file is ignored when missing, whatever the host has
+        std::env::remove_var(CONFIG_FILE_ENV);
+        let cli = parse_with(|cmd| {
+            Ok(cmd
+                .mut_arg("config", |a| a.default_value(missing))
+                .try_get_matches_from(["kanto-auto-deployer"])?)
+        });
+        assert_eq!(cli.unwrap().config, PathBuf::from(missing));
+    }
+}
@@ -0,0 +1,124 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! The lock file that enables the auto-deployer.
+//!
+//! KAD is enabled while `KAD.enabled` exists. When VUM takes over, the lock is renamed to
+//! `KAD.disabled`. Every change of the lock is written to an audit log next to it.
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+static AUDIT_LOG_FILE: &str = "audit.log";
+
+/// Can be changed at build time with the `KAD_DEFAULT_LOCK_PATH` environment variable
+/// (not `KAD_LOCK_PATH`, which sets `--lock-path` at runtime)
+pub static DEFAULT_LOCK_PATH: &str = match std::option_env!("KAD_DEFAULT_LOCK_PATH") {
+    Some(p) => p,
+    None => "/var/lib/kanto-auto-deployer/KAD.enabled",
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockState {
+    Enabled,
+    /// Disabled, usually because VUM took over
+    Disabled,
+    /// Neither the enabled nor the disabled lock exists
+    Missing,
+}
+
+impl Display for LockState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str_repr = match self {
+            LockState::Enabled => "enabled",
+            LockState::Disabled => "disabled",
+            LockState::Missing => "not enabled (no lock)",
+        };
+        write!(f, "{}", str_repr)
+    }
+}
+
+fn disabled_path(lock_path: &Path) -> PathBuf {
+    lock_path.with_extension("disabled")
+}
+
+pub fn kad_enabled(lock_path: &Path) -> bool {
+    lock_path.exists() && lock_path.is_file()
+}
+
+pub fn lock_state(lock_path: &Path) -> LockState {
+    if kad_enabled(lock_path) {
+        LockState::Enabled
+    } else if disabled_path(lock_path).is_file() {
+        LockState::Disabled
+    } else {
+        LockState::Missing
+    }
+}
+
+/// Appends the change to the audit log. Failing to do so does not undo the change, but is logged.
+fn audit(lock_path: &Path, action: &str, source: &str) {
+    log::warn!(target: "kanto_auto_deployer::audit", "KAD {} by {}", action, source);
+    let audit_path = lock_path.with_file_name(AUDIT_LOG_FILE);
+    let entry = format!(
+        "{} {} by {}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        action,
+        source
+    );
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&audit_path)
+        .and_then(|mut f| f.write_all(entry.as_bytes()));
+    if let Err(e) = written {
+        log::error!("Could not write audit log {:?}: {}", audit_path, e);
+    }
+}
+
+/// Enables KAD, restoring the disabled lock if there is one. Returns whether anything changed.
+pub fn enable(lock_path: &Path, source: &str) -> Result<bool> {
+    let disabled = disabled_path(lock_path);
+    match lock_state(lock_path) {
+        LockState::Enabled => {
+            if disabled.exists() {
+                // Left-over of an interrupted change, the enabled lock wins
+                fs::remove_file(&disabled)?;
+            }
+            return Ok(false);
+        }
+        LockState::Disabled => fs::rename(&disabled, lock_path)?,
+        LockState::Missing => {
+            if let Some(parent) = lock_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::File::create(lock_path)
+                .map_err(|e| anyhow!("Could not create lock {:?}: {}", lock_path, e))?;
+        }
+    }
+    audit(lock_path, "enabled", source);
+    Ok(true)
+}
+
+/// Disables KAD by renaming the lock to `*.disabled`. Returns whether anything changed.
+pub fn disable(lock_path: &Path, source: &str) -> Result<bool> {
+    if !kad_enabled(lock_path) {
+        return Ok(false);
+    }
+    fs::rename(lock_path, disabled_path(lock_path))?;
+    audit(lock_path, "disabled", source);
+    Ok(true)
+}
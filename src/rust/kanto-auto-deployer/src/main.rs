@@ -10,13 +10,15 @@
 // *
 // * SPDX-License-Identifier: Apache-2.0
 // ********************************************************************************
-use glob::glob;
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
 use std::path::{PathBuf, Path};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
-use clap::Parser;
-use futures::future;
+use clap::{Parser, Subcommand};
+use futures::future::{self, FutureExt, LocalBoxFuture, Shared};
 use std::sync::atomic::AtomicBool;
 use tokio::net::UnixStream;
 use tokio_retry::{strategy, RetryIf};
@@ -28,8 +30,16 @@ use clap::Args;
 #[cfg(feature = "mqtt")]
 use std::thread;
 
+#[cfg(feature = "mqtt")]
+pub mod desired_state_agent;
+#[cfg(feature = "mqtt")]
+pub mod kad_lock;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_connection;
 #[cfg(feature = "mqtt")]
 pub mod mqtt_listener;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_publisher;
 pub mod containers {
     //This is a hack because tonic has an issue with deeply nested protobufs
     tonic::include_proto!("mod");
@@ -37,19 +47,116 @@ pub mod containers {
 
 #[cfg(feature = "filewatcher")]
 pub mod fs_watcher;
-#[cfg(feature = "filewatcher")]
-use fs_watcher::is_filetype;
 
+pub mod container_diff;
+pub mod deploy_order;
+pub mod deploy_plan;
+pub mod discovery;
+pub mod kad_config;
 pub mod manifest_parser;
+pub mod manifest_validator;
+pub mod ownership;
+pub mod readiness;
+pub mod signals;
+pub mod status_report;
+pub mod systemd;
+
+use deploy_plan::{PlanAction, PlanEntry, PlanFormat};
+use discovery::ManifestFilter;
+use manifest_parser::ManifestSyntax;
+use status_report::{ContainerStatus, LastAction, StatusStore};
 
 use containers::github::com::eclipse_kanto::container_management::containerm::api::services::containers as kanto;
 use containers::github::com::eclipse_kanto::container_management::containerm::api::types::containers as kanto_cnt;
 
 type CmClient = kanto::containers_client::ContainersClient<tonic::transport::Channel>;
 
+/// Maps each deployed manifest path to the names of the containers it describes, so that
+/// the containers can be cleaned-up when the manifest gets deleted in daemon mode.
+/// Only kept in memory: the containers of manifests deleted while not running are left to prune.
+type DeployedManifests = Mutex<HashMap<PathBuf, Vec<String>>>;
+
+/// Outcome of a single deployment that can be awaited by every container depending on it
+type DeployResult = std::result::Result<Deployed, Arc<anyhow::Error>>;
+type SharedDeployment<'a> = Shared<LocalBoxFuture<'a, DeployResult>>;
+
+/// A container that is (now) deployed as described by its manifest, or skipped
+#[derive(Debug, Clone)]
+pub struct Deployed {
+    name: String,
+    id: String,
+    action: PlanAction,
+    /// From the manifest, kept with the ownership of the container
+    stop: manifest_parser::StopSettings,
+}
+
+/// Settings that apply to every deployment done by this instance
+#[derive(Clone)]
+pub struct DeploySettings {
+    socket: String,
+    retries: RetryTimes,
+    readiness: Option<readiness::ReadinessCheck>,
+    variables: manifest_parser::Variables,
+    ownership: Arc<ownership::Ownership>,
+    /// Also change the containers that were not created by the auto-deployer
+    force: bool,
+    /// Take over the containers that were not created by the auto-deployer but match their manifest
+    adopt: bool,
+    /// How containers are stopped, unless their manifest says otherwise
+    stop: kanto_cnt::StopOptions,
+    /// Held while changing containers, so that the daemon and the desired state agent
+    /// do not deploy at the same time
+    #[cfg_attr(not(feature = "filewatcher"), allow(dead_code))]
+    deploying: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl DeploySettings {
+    /// The stop options of a container, with the defaults for the ones its manifest does not set
+    fn stop_options(&self, stop: &manifest_parser::StopSettings) -> kanto_cnt::StopOptions {
+        kanto_cnt::StopOptions {
+            timeout: stop.timeout.unwrap_or(self.stop.timeout),
+            force: stop.force.unwrap_or(self.stop.force),
+            signal: stop
+                .signal
+                .clone()
+                .unwrap_or_else(|| self.stop.signal.clone()),
+        }
+    }
+
+    /// Whether an existing container may be changed to match its manifest
+    fn may_change(
+        &self,
+        new_cont: &kanto_cnt::Container,
+        existing_cont: &kanto_cnt::Container,
+    ) -> bool {
+        self.force
+            || self.ownership.owns(&existing_cont.name, &existing_cont.id)
+            || (self.adopt && container_diff::diff_containers(new_cont, existing_cont).is_empty())
+    }
+}
+
 #[derive(Parser, Debug)]
-#[clap(version, about)]
+#[clap(
+    version,
+    about,
+    after_help = "Every option can also be set with a KAD_ environment variable (shown for each option) \
+or in the configuration file, see the README. The command line takes precedence over the environment, \
+which takes precedence over the configuration file."
+)]
 pub struct CliArgs {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// TOML configuration file with the values for the options not set on the command line
+    /// or in the environment. Ignored if it does not exist, unless set explicitly
+    #[clap(long, short, env = kad_config::CONFIG_FILE_ENV, default_value = kad_config::DEFAULT_CONFIG_FILE)]
+    config: PathBuf,
+
+    /// Log level, or a filter in the RUST_LOG syntax (e.g. "kanto_auto_deployer=debug").
+    /// Defaults to RUST_LOG, or "info" if that is not set either
+    #[clap(long)]
+    log_level: Option<String>,
+
     /// Set the path to the directory containing the manifests
     #[clap(default_value = ".")]
     manifests_path: PathBuf,
@@ -68,16 +175,148 @@ pub struct CliArgs {
     #[cfg(feature = "filewatcher")]
     daemon: bool,
 
+    /// How often to try connecting to Kanto-CM: a number of attempts, "forever" or "never"
+    /// (a single attempt). Defaults to "forever" in daemon mode and "never" otherwise
+    #[clap(long, value_name = "ATTEMPTS")]
+    retries: Option<RetryTimes>,
+
+    /// How to detect manifest changes in daemon mode. Falls back to polling if inotify can not be used
+    #[clap(long, arg_enum, default_value = "inotify")]
+    #[cfg(feature = "filewatcher")]
+    watch_backend: fs_watcher::WatchBackend,
+
+    /// Milliseconds to wait for further changes to a manifest before redeploying it
+    #[clap(long, default_value_t = 500)]
+    #[cfg(feature = "filewatcher")]
+    debounce_ms: u64,
+
+    /// Seconds between two scans of the manifests directory when polling
+    #[clap(long, default_value_t = fs_watcher::POLL_SECONDS)]
+    #[cfg(feature = "filewatcher")]
+    poll_interval_secs: f64,
+
+    /// Seconds between two checks in daemon mode that the containers of all manifests exist and
+    /// are running. Missing containers are recreated and stopped ones are started. Off by default
+    #[clap(long, value_name = "SECS")]
+    #[cfg(feature = "filewatcher")]
+    reconcile_interval_secs: Option<u64>,
+
+    /// Only print what the deployment would do for each manifest (create, start or leave unchanged),
+    /// without changing any containers
+    #[clap(long, short = 'n', action, default_value_t = false, global = true)]
+    dry_run: bool,
+
+    /// Output format of the dry-run plan
+    #[clap(long, arg_enum, default_value = "text", global = true)]
+    plan_format: PlanFormat,
+
+    /// After starting a container, wait until it has been running for the settle time.
+    /// Containers that exit with an error, get OOM-killed or crash-loop count as failed deployments
+    #[clap(long, action, default_value_t = false)]
+    wait_ready: bool,
+
+    /// Seconds a started container has to be running without interruption to be considered ready
+    #[clap(long, default_value_t = 5)]
+    ready_settle_secs: u64,
+
+    /// Seconds to wait for a started container to become ready before failing its deployment
+    #[clap(long, default_value_t = 60)]
+    ready_timeout_secs: u64,
+
+    /// File with KEY=VALUE lines providing the values for ${VAR} references in manifests.
+    /// The values from the file take precedence over environment variables
+    #[clap(long)]
+    vars_file: Option<PathBuf>,
+
+    /// Only deploy the manifests whose path relative to the manifests directory matches
+    /// one of these glob patterns, e.g. "system/**". Can be repeated
+    #[clap(long, value_name = "GLOB", global = true)]
+    include: Vec<String>,
+
+    /// Skip the manifests whose path relative to the manifests directory matches
+    /// one of these glob patterns, e.g. "disabled/**". Can be repeated
+    #[clap(long, value_name = "GLOB", global = true)]
+    exclude: Vec<String>,
+
+    /// File to which the outcome of the last deployment of every manifest is written.
+    /// The containers created by the auto-deployer are recorded in ownership.json next to it
+    #[clap(long, default_value = status_report::DEFAULT_STATUS_FILE)]
+    status_file: PathBuf,
+
+    /// Also recreate, start and remove containers that were not created by the auto-deployer,
+    /// taking them over
+    #[clap(long, action, global = true)]
+    force: bool,
+
+    /// Take over the containers that were not created by the auto-deployer but already match
+    /// their manifest, e.g. deployed by a version that did not record ownership
+    #[clap(long, action)]
+    adopt: bool,
+
+    /// Seconds a container is given to stop when it is recreated or removed, unless its
+    /// manifest sets another one
+    #[clap(long, value_name = "SECS", default_value_t = 1)]
+    stop_timeout_secs: i64,
+
+    /// Signal that stops a container when it is recreated or removed, unless its manifest
+    /// sets another one
+    #[clap(long, value_name = "SIGNAL", default_value = "SIGTERM")]
+    stop_signal: String,
+
+    /// Whether to kill a container that has not stopped within the stop timeout, unless its
+    /// manifest says otherwise. Otherwise its recreation or removal fails
+    #[clap(long, value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true)]
+    stop_force: bool,
+
+    /// After the initial deployment, remove the containers created by the auto-deployer whose
+    /// manifest is no longer in the directory, as the prune subcommand does (without asking)
+    #[clap(long, action)]
+    prune: bool,
+
+    /// Lock file that has to exist for the auto-deployer to be enabled, see the enable subcommand
+    #[cfg(feature = "mqtt")]
+    #[clap(long, default_value = kad_lock::DEFAULT_LOCK_PATH)]
+    lock_path: PathBuf,
+
     #[cfg(feature = "mqtt")]
     #[clap(flatten)]
     mqtt: MQTTconfig,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check manifests without deploying them or connecting to Kanto-CM.
+    /// Exits with a non-zero code if any manifest has errors or unknown keys
+    Validate {
+        /// Manifest file or directory containing the manifests. Defaults to the manifests path
+        path: Option<PathBuf>,
+    },
+    /// Print the outcome of the last deployment of every manifest, as recorded in the status file
+    Status {
+        /// Output format
+        #[clap(long, arg_enum, default_value = "text")]
+        format: PlanFormat,
+    },
+    /// Remove the containers created by the auto-deployer whose manifest is no longer in the
+    /// directory. Lists them and asks for confirmation first, only lists them with --dry-run
+    Prune {
+        /// Do not ask for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+    },
+    /// Enable the auto-deployer again, e.g. after it was disabled because VUM took over
+    #[cfg(feature = "mqtt")]
+    Enable,
+    /// Disable the auto-deployer, as if VUM took over
+    #[cfg(feature = "mqtt")]
+    Disable,
+}
+
 #[cfg(feature = "mqtt")]
 #[derive(Debug, Args)]
 pub struct MQTTconfig {
     /// Enable an MQTT client that listens for the desired state message and disables kanto-auto-deployer to avoid conflicts
-    #[clap(short = 'm', long = "mqtt")]
+    #[clap(short = 'm', long = "mqtt", action)]
     enabled: bool,
 
     /// Hostname/IP to the MQTT broker where the desired state message would be posted
@@ -94,9 +333,77 @@ pub struct MQTTconfig {
         default_value = "containersupdate/desiredstatefeedback"
     )]
     topic: String,
+
+    /// Publish an event for every container deployed or removed, and a summary of each
+    /// deployment run, on this topic
+    #[clap(long = "mqtt-events-topic")]
+    events_topic: Option<String>,
+
+    /// Listen for commands on this topic, also while disabled. A message with the payload
+    /// {"command": "enable", "reason": "..."} in the VUM envelope re-enables the auto-deployer
+    #[clap(long = "mqtt-command-topic")]
+    command_topic: Option<String>,
+
+    /// Act as the update agent of the "containers" domain in daemon mode: apply the desired states
+    /// published on --mqtt-desired-state-topic and report the outcome on
+    /// --mqtt-desired-state-feedback-topic. For devices without the update agent stack
+    #[clap(long = "mqtt-desired-state", action)]
+    desired_state: bool,
+
+    /// Topic on which to subscribe for the desired states to apply
+    #[clap(
+        long = "mqtt-desired-state-topic",
+        default_value = "vehicleupdate/desiredstate"
+    )]
+    desired_state_topic: String,
+
+    /// Topic on which to publish the feedback for the applied desired states
+    #[clap(
+        long = "mqtt-desired-state-feedback-topic",
+        default_value = "vehicleupdate/desiredstatefeedback"
+    )]
+    desired_state_feedback_topic: String,
+
+    /// Client id used to connect to the MQTT broker
+    #[clap(long = "mqtt-client-id", default_value = "kanto_auto_deployer")]
+    client_id: String,
+
+    /// Connect to the MQTT broker over TLS, verifying the broker with the system CA certificates
+    /// unless --mqtt-ca-file is set
+    #[clap(long = "mqtt-tls", action)]
+    tls: bool,
+
+    /// PEM file with the CA certificate(s) to verify the MQTT broker with. Implies --mqtt-tls
+    #[clap(long = "mqtt-ca-file")]
+    ca_file: Option<PathBuf>,
+
+    /// PEM file with the client certificate for mutual TLS. Implies --mqtt-tls
+    #[clap(long = "mqtt-client-cert")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the client certificate
+    #[clap(long = "mqtt-client-key")]
+    client_key: Option<PathBuf>,
+
+    /// Username to authenticate with at the MQTT broker
+    #[clap(long = "mqtt-username")]
+    username: Option<String>,
+
+    /// Password to authenticate with at the MQTT broker
+    #[clap(long = "mqtt-password")]
+    password: Option<mqtt_connection::Secret>,
+}
+
+#[cfg(feature = "mqtt")]
+impl MQTTconfig {
+    fn tls_enabled(&self) -> bool {
+        self.tls || self.ca_file.is_some() || self.client_cert.is_some()
+    }
 }
 
 static CM_RETRY_BASE_TIMEOUT_MS: u64 = 100;
+#[cfg(feature = "mqtt")]
+static THREAD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 // Conditional compilation would give warnings for unused variants
 #[allow(dead_code)]
 #[derive(Clone, Copy, PartialEq)]
@@ -106,6 +413,33 @@ pub enum RetryTimes {
     Never,
 }
 
+impl std::str::FromStr for RetryTimes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "forever" => Ok(RetryTimes::Forever),
+            "never" => Ok(RetryTimes::Never),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(anyhow::anyhow!(
+                    "expected a number of attempts, \"forever\" or \"never\""
+                )),
+                Ok(count) => Ok(RetryTimes::Count(count)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for RetryTimes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetryTimes::Count(count) => write!(f, "{}", count),
+            RetryTimes::Forever => write!(f, "forever"),
+            RetryTimes::Never => write!(f, "never"),
+        }
+    }
+}
+
 struct RetryState {
     retry_times: RetryTimes,
 }
@@ -117,6 +451,9 @@ impl RetryState {
 
     // Updates the count and returns true if the caller should stop retrying
     fn tick(&mut self) -> bool {
+        if signals::shutting_down() {
+            return false;
+        }
         match self.retry_times {
             RetryTimes::Forever => true,
             RetryTimes::Never => false,
@@ -174,16 +511,27 @@ async fn start(_client: &mut CmClient, name: &str, _id: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn stop(_client: &mut CmClient, id: &str, timeout: i64) -> Result<()> {
-    let stop_options = Some(kanto_cnt::StopOptions {
-        timeout,
-        force: true,
-        signal: String::from("SIGTERM"),
-    });
+async fn start_and_wait(
+    _client: &mut CmClient,
+    name: &str,
+    id: &str,
+    settings: &DeploySettings,
+) -> Result<()> {
+    start(_client, name, id).await?;
+    if let Some(check) = &settings.readiness {
+        readiness::wait_until_ready(_client, name, id, check).await?;
+    }
+    Ok(())
+}
 
+pub async fn stop(
+    _client: &mut CmClient,
+    id: &str,
+    stop_options: kanto_cnt::StopOptions,
+) -> Result<()> {
     let _r = tonic::Request::new(kanto::StopContainerRequest {
         id: String::from(id),
-        stop_options,
+        stop_options: Some(stop_options),
     });
     let _r = _client.stop(_r).await?;
     Ok(())
@@ -198,7 +546,7 @@ pub async fn remove(_client: &mut CmClient, id: &str) -> Result<()> {
     Ok(())
 }
 
-fn container_running(c: &kanto_cnt::Container) -> bool {
+pub(crate) fn container_running(c: &kanto_cnt::Container) -> bool {
     if let Some(state) = &c.state {
         return state.running;
     }
@@ -207,31 +555,188 @@ fn container_running(c: &kanto_cnt::Container) -> bool {
 
 async fn handle_existing(
     _client: &mut CmClient,
+    source: &Path,
     new_cont: kanto_cnt::Container,
     existing_cont: &kanto_cnt::Container,
+    stop_settings: &manifest_parser::StopSettings,
     recreate: bool,
-) -> Result<()> {
+    settings: &DeploySettings,
+) -> Result<(PlanAction, String)> {
     log::info!("Already exists [{}]", &new_cont.name);
-    if !recreate {
-        // If we do not wish to recreate the container only start it if needed
-        // and return early
-        log::debug!("Skipping {}", &new_cont.name);
-        if !container_running(existing_cont) {
-            start(_client, &new_cont.name, &existing_cont.id).await?;
+    if !settings
+        .ownership
+        .owns(&existing_cont.name, &existing_cont.id)
+    {
+        if !settings.may_change(&new_cont, existing_cont) {
+            log::warn!(
+                "[{}] was not created by the auto-deployer, leaving it as is (see --force)",
+                &new_cont.name
+            );
+            return Ok((PlanAction::Skip, existing_cont.id.clone()));
         }
-        return Ok(());
+        log::warn!(
+            "Taking over [{}], which was not created by the auto-deployer",
+            &new_cont.name
+        );
     }
-    if container_running(existing_cont) {
-        log::debug!("Stopping [{}]", &new_cont.name);
-        stop(_client, &existing_cont.id, 1).await?;
+    let action = deploy_plan::plan_action(&new_cont, Some(existing_cont), recreate);
+    match action {
+        PlanAction::Recreate => {
+            for change in container_diff::diff_containers(&new_cont, existing_cont) {
+                log::info!("Changed in [{}]: {}", &new_cont.name, change);
+            }
+        }
+        PlanAction::Start => {
+            // If we do not wish to recreate the container only start it if needed
+            // and return early
+            log::debug!("Skipping {}", &new_cont.name);
+            start_and_wait(_client, &new_cont.name, &existing_cont.id, settings).await?;
+            return Ok((action, existing_cont.id.clone()));
+        }
+        _ => {
+            log::debug!("Skipping {}", &new_cont.name);
+            return Ok((action, existing_cont.id.clone()));
+        }
     }
-    log::info!("Removing [{}]", &new_cont.name);
+    // Keep the previous definition around, so it can be restored if the new one fails
+    let snapshot = rollback_snapshot(existing_cont);
+    let was_running = container_running(existing_cont);
+    let name = new_cont.name.clone();
+    // As deployed, the stop settings of its manifest may have changed since. A container that
+    // is taken over is owned from now on, also if it has to be restored.
+    let previous = settings
+        .ownership
+        .get(&name)
+        .filter(|c| c.id == existing_cont.id)
+        .unwrap_or_else(|| ownership::OwnedContainer {
+            id: existing_cont.id.clone(),
+            manifest: source.to_path_buf(),
+            content_hash: None,
+            timestamp: status_report::now(),
+            stop: stop_settings.clone(),
+        });
+    if was_running {
+        log::debug!("Stopping [{}]", &name);
+        stop(
+            _client,
+            &existing_cont.id,
+            settings.stop_options(&previous.stop),
+        )
+        .await?;
+    }
+    log::info!("Removing [{}]", &name);
     remove(_client, &existing_cont.id).await?;
-    deploy_new(_client, new_cont).await?;
+    let id = match deploy_new(_client, source, new_cont, stop_settings, settings).await {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("Failed to recreate [{}]: {:?}. Rolling back", &name, e);
+            rollback(
+                _client,
+                snapshot,
+                previous,
+                was_running,
+                stop_settings,
+                settings,
+            )
+            .await
+            .map_err(|rollback_err| {
+                anyhow::anyhow!(
+                    "Recreating [{}] failed ({}) and so did the rollback: {}",
+                    name,
+                    e,
+                    rollback_err
+                )
+            })?;
+            return Err(anyhow::anyhow!(
+                "Recreating [{}] failed ({}), rolled back to the previous version",
+                name,
+                e
+            ));
+        }
+    };
+    Ok((action, id))
+}
+
+/// Copy of an existing container's definition without the fields that Kanto-CM
+/// fills-in itself, so that it can be created again.
+fn rollback_snapshot(existing_cont: &kanto_cnt::Container) -> kanto_cnt::Container {
+    kanto_cnt::Container {
+        id: String::new(),
+        resolv_conf_path: String::new(),
+        hosts_path: String::new(),
+        hostname_path: String::new(),
+        network_settings: None,
+        state: Some(kanto_cnt::State {
+            pid: -1,
+            ..Default::default()
+        }),
+        created: String::new(),
+        manually_stopped: false,
+        restart_count: 0,
+        ..existing_cont.clone()
+    }
+}
+
+/// Removes what is left of a failed recreate and restores the previous container, owned as
+/// `previous` was. `stop_settings` are the ones of the new manifest, that the failed container
+/// was created from.
+async fn rollback(
+    _client: &mut CmClient,
+    snapshot: kanto_cnt::Container,
+    previous: ownership::OwnedContainer,
+    was_running: bool,
+    stop_settings: &manifest_parser::StopSettings,
+    settings: &DeploySettings,
+) -> Result<()> {
+    let _r = tonic::Request::new(kanto::ListContainersRequest {});
+    let containers_list = _client.list(_r).await?.into_inner().containers;
+    if let Some(failed_cont) = containers_list.iter().find(|c| c.name == snapshot.name) {
+        if container_running(failed_cont) {
+            log::debug!("Stopping failed [{}]", &snapshot.name);
+            stop(
+                _client,
+                &failed_cont.id,
+                settings.stop_options(stop_settings),
+            )
+            .await?;
+        }
+        log::info!("Removing failed [{}]", &snapshot.name);
+        remove(_client, &failed_cont.id).await?;
+    }
+
+    let name = snapshot.name.clone();
+    log::info!("Restoring previous version of [{}]", &name);
+    let request = tonic::Request::new(kanto::CreateContainerRequest {
+        container: Some(snapshot),
+    });
+    let _response = _client.create(request).await?;
+    let id = match _response.into_inner().container {
+        Some(c) => c.id,
+        None => String::new(),
+    };
+    settings.ownership.record(
+        &name,
+        &id,
+        &previous.manifest,
+        previous.content_hash,
+        previous.stop,
+    );
+    if was_running {
+        start_and_wait(_client, &name, &id, settings).await?;
+    }
+    log::warn!("Rolled back [{}] to its previous version", &name);
     Ok(())
 }
 
-async fn deploy_new(_client: &mut CmClient, new_cont: kanto_cnt::Container) -> Result<()> {
+/// The container is owned as soon as it is created, so that it is still recreated once its
+/// manifest is fixed if it fails to start.
+async fn deploy_new(
+    _client: &mut CmClient,
+    source: &Path,
+    new_cont: kanto_cnt::Container,
+    stop_settings: &manifest_parser::StopSettings,
+    settings: &DeploySettings,
+) -> Result<String> {
     let new_cont_name = new_cont.name.clone();
     log::info!("Creating [{}]", &new_cont_name);
     let request = tonic::Request::new(kanto::CreateContainerRequest {
@@ -243,55 +748,468 @@ async fn deploy_new(_client: &mut CmClient, new_cont: kanto_cnt::Container) -> R
         Some(c) => c.id,
         None => String::new(),
     };
-    start(_client, &new_cont_name, &id).await?;
-    Ok(())
+    settings
+        .ownership
+        .record(&new_cont_name, &id, source, None, stop_settings.clone());
+    start_and_wait(_client, &new_cont_name, &id, settings).await?;
+    Ok(id)
 }
 
-async fn deploy(socket: &str, retries: RetryTimes, file_path: &Path, recreate: bool) -> Result<()> {
-    let container_str = tokio::fs::read_to_string(file_path).await?;
-    let mut _client = get_client(socket, retries).await?;
-    let parsed_json = manifest_parser::try_parse_manifest(&container_str);
-    if let Ok(new_container) = parsed_json {
-        let _r = tonic::Request::new(kanto::ListContainersRequest {});
-        let containers_list = _client.list(_r).await?.into_inner().containers;
-        let existing_instance = containers_list
-            .iter()
-            .find(|c| c.name == new_container.name);
-        if let Some(existing_cont) = existing_instance {
-            handle_existing(&mut _client, new_container, existing_cont, recreate).await
-        } else {
-            deploy_new(&mut _client, new_container).await
-        }
-    } else {
-        Err(anyhow::anyhow!("Wrong json in [{:?}]", file_path))
+fn parse_manifest(
+    file_path: &Path,
+    container_str: &str,
+    variables: &manifest_parser::Variables,
+) -> Result<Vec<manifest_parser::Manifest>> {
+    let syntax = ManifestSyntax::from_path(file_path)
+        .ok_or_else(|| anyhow::anyhow!("Unsupported manifest file type [{:?}]", file_path))?;
+    manifest_parser::try_parse_manifest(container_str, syntax, variables)
+        .map_err(|e| anyhow::anyhow!("Invalid {} manifest [{:?}]: {}", syntax, file_path, e))
+}
+
+/// A manifest file as read for deployment
+struct ManifestFile {
+    /// Unset if the file could not be read
+    content_hash: Option<String>,
+    parsed: Result<Vec<manifest_parser::Manifest>>,
+}
+
+async fn read_manifest(file_path: &Path, variables: &manifest_parser::Variables) -> ManifestFile {
+    match tokio::fs::read_to_string(file_path).await {
+        Ok(container_str) => ManifestFile {
+            content_hash: Some(status_report::content_hash(&container_str)),
+            parsed: parse_manifest(file_path, &container_str, variables),
+        },
+        Err(e) => ManifestFile {
+            content_hash: None,
+            parsed: Err(e.into()),
+        },
     }
 }
 
-async fn deploy_directory(directory_path: &str, socket: &str, retries: RetryTimes) -> Result<()> {
-    let manifest_glob = format!("{}/*.json", directory_path);
-    log::info!("Reading manifests from [{}]", directory_path);
+async fn deploy_container(
+    settings: &DeploySettings,
+    source: &Path,
+    new_container: kanto_cnt::Container,
+    stop_settings: manifest_parser::StopSettings,
+    recreate: bool,
+) -> Result<Deployed> {
+    let mut _client = get_client(&settings.socket, settings.retries).await?;
+    let name = new_container.name.clone();
+    let _r = tonic::Request::new(kanto::ListContainersRequest {});
+    let containers_list = _client.list(_r).await?.into_inner().containers;
+    let existing_instance = containers_list
+        .iter()
+        .find(|c| c.name == new_container.name);
+    let (action, id) = if let Some(existing_cont) = existing_instance {
+        handle_existing(
+            &mut _client,
+            source,
+            new_container,
+            existing_cont,
+            &stop_settings,
+            recreate,
+            settings,
+        )
+        .await?
+    } else {
+        (
+            PlanAction::Create,
+            deploy_new(
+                &mut _client,
+                source,
+                new_container,
+                &stop_settings,
+                settings,
+            )
+            .await?,
+        )
+    };
+    Ok(Deployed {
+        name,
+        id,
+        action,
+        stop: stop_settings,
+    })
+}
 
-    let found_manifest_paths: Vec<PathBuf> = glob(&manifest_glob)?
-        .filter_map(Result::ok)
+/// Deploys all manifests, each one only after the containers it depends on were deployed.
+/// Independent manifests are still deployed concurrently. The results are in the order of
+/// the provided manifests, which come with the file (or desired state) that owns them.
+async fn deploy_manifests(
+    settings: &DeploySettings,
+    manifests: Vec<Result<(PathBuf, manifest_parser::Manifest)>>,
+    recreate: bool,
+) -> Vec<DeployResult> {
+    let nodes: Vec<(&str, &[String])> = manifests
+        .iter()
+        .map(|m| match m {
+            Ok((_, m)) => (m.container.name.as_str(), m.options.depends_on.as_slice()),
+            Err(_) => ("", [].as_slice()),
+        })
         .collect();
+    let deploy_order = deploy_order::resolve(&nodes);
+
+    let mut manifests: Vec<Option<Result<(PathBuf, manifest_parser::Manifest)>>> =
+        manifests.into_iter().map(Some).collect();
+    let mut scheduled: HashMap<String, SharedDeployment> = HashMap::new();
+    let mut deployments: Vec<Option<SharedDeployment>> = vec![None; manifests.len()];
+
+    for idx in deploy_order.order {
+        let deployment = match manifests[idx].take() {
+            Some(Ok((source, m))) => {
+                let name = m.container.name.clone();
+                let rejected = if deploy_order.duplicates.contains(&idx) {
+                    Some(format!("[{}] is defined by more than one manifest", name))
+                } else {
+                    deploy_order
+                        .cycles
+                        .get(&idx)
+                        .map(|cycle| format!("[{}] is part of a dependency cycle: {}", name, cycle))
+                };
+                let deployment = match rejected {
+                    Some(e) => future::ready(Err(Arc::new(anyhow::anyhow!(e)))).boxed_local(),
+                    None => {
+                        let dependencies: Vec<(String, SharedDeployment)> = m
+                            .options
+                            .depends_on
+                            .iter()
+                            .filter_map(|dep| match scheduled.get(dep) {
+                                Some(d) => Some((dep.clone(), d.clone())),
+                                None => {
+                                    log::warn!(
+                                        "[{}] depends on [{}] which has no manifest, assuming it is already deployed",
+                                        name,
+                                        dep
+                                    );
+                                    None
+                                }
+                            })
+                            .collect();
+                        async move {
+                            for (dep_name, dependency) in dependencies {
+                                if dependency.await.is_err() {
+                                    return Err(Arc::new(anyhow::anyhow!(
+                                        "Not deploying [{}] as its dependency [{}] failed",
+                                        m.container.name,
+                                        dep_name
+                                    )));
+                                }
+                            }
+                            if signals::shutting_down() {
+                                return Err(Arc::new(anyhow::anyhow!(
+                                    "Not deploying [{}], shutting down",
+                                    m.container.name
+                                )));
+                            }
+                            deploy_container(
+                                settings,
+                                &source,
+                                m.container,
+                                m.options.stop,
+                                recreate,
+                            )
+                            .await
+                            .map_err(Arc::new)
+                        }
+                        .boxed_local()
+                    }
+                }
+                .shared();
+                scheduled.insert(name, deployment.clone());
+                deployment
+            }
+            Some(Err(e)) => future::ready(Err(Arc::new(e))).boxed_local().shared(),
+            None => continue,
+        };
+        deployments[idx] = Some(deployment);
+    }
+
+    future::join_all(deployments.into_iter().flatten()).await
+}
+
+/// Stops (if needed) and removes the container with the given name.
+/// Used when the manifest that created the container is no longer present.
+async fn undeploy(settings: &DeploySettings, name: &str) -> Result<()> {
+    let mut _client = get_client(&settings.socket, settings.retries).await?;
+    let _r = tonic::Request::new(kanto::ListContainersRequest {});
+    let containers_list = _client.list(_r).await?.into_inner().containers;
+    let existing_cont = match containers_list.iter().find(|c| c.name == name) {
+        Some(c) => c,
+        None => {
+            log::warn!("Container [{}] does not exist, nothing to remove", name);
+            settings.ownership.forget(name);
+            return Ok(());
+        }
+    };
+    if !settings.ownership.owns(name, &existing_cont.id) && !settings.force {
+        return Err(anyhow::anyhow!(
+            "Not removing [{}], it was not created by the auto-deployer (see --force)",
+            name
+        ));
+    }
+    if container_running(existing_cont) {
+        log::debug!("Stopping [{}]", name);
+        // The manifest is gone, the stop settings it had were recorded with the ownership
+        let stop_settings = settings
+            .ownership
+            .get(name)
+            .map(|c| c.stop)
+            .unwrap_or_default();
+        stop(
+            &mut _client,
+            &existing_cont.id,
+            settings.stop_options(&stop_settings),
+        )
+        .await?;
+    }
+    log::info!("Removing [{}]", name);
+    remove(&mut _client, &existing_cont.id).await?;
+    settings.ownership.forget(name);
+    Ok(())
+}
+
+fn find_manifests(filter: &ManifestFilter) -> Result<Vec<PathBuf>> {
+    let directory_path = filter.root();
+    log::info!("Reading manifests from [{}]", directory_path.display());
+
+    let found_manifest_paths = filter.find(directory_path)?;
     if found_manifest_paths.is_empty() {
-        return Err(anyhow::anyhow!("No manifests found in {directory_path}"));
+        return Err(anyhow::anyhow!(
+            "No manifests found in {}",
+            directory_path.display()
+        ));
+    }
+    Ok(found_manifest_paths)
+}
+
+/// Prints the diagnostics for every manifest. Returns whether all of them are free of problems.
+fn validate_manifests(
+    path: &Path,
+    variables: &manifest_parser::Variables,
+    include: &[String],
+    exclude: &[String],
+) -> Result<bool> {
+    let manifest_paths = if path.is_dir() {
+        find_manifests(&ManifestFilter::new(path, include, exclude)?)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut with_problems = 0;
+    for manifest_path in &manifest_paths {
+        let report = match std::fs::read_to_string(manifest_path) {
+            Ok(content) => {
+                manifest_validator::validate_manifest(manifest_path, &content, variables)
+            }
+            Err(e) => manifest_validator::ValidationReport::unreadable(manifest_path, &e),
+        };
+        print!("{}", report);
+        if report.has_problems() {
+            with_problems += 1;
+        }
+    }
+    println!(
+        "Checked {} manifest(s), {} with problems",
+        manifest_paths.len(),
+        with_problems
+    );
+    Ok(with_problems == 0)
+}
+
+fn plan_entry(
+    path: &Path,
+    new_container: kanto_cnt::Container,
+    containers_list: &[kanto_cnt::Container],
+    settings: &DeploySettings,
+    recreate: bool,
+) -> PlanEntry {
+    let existing = containers_list
+        .iter()
+        .find(|c| c.name == new_container.name);
+    if let Some(c) = existing {
+        if !settings.may_change(&new_container, c) {
+            return PlanEntry {
+                manifest: path.to_path_buf(),
+                container: Some(new_container.name),
+                action: PlanAction::Skip,
+                changes: Vec::new(),
+                error: Some(String::from(
+                    "Not created by the auto-deployer (see --force)",
+                )),
+            };
+        }
+    }
+    let action = deploy_plan::plan_action(&new_container, existing, recreate);
+    let changes = match existing {
+        Some(c) if action == PlanAction::Recreate => {
+            container_diff::diff_containers(&new_container, c)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    PlanEntry {
+        manifest: path.to_path_buf(),
+        container: Some(new_container.name),
+        action,
+        changes,
+        error: None,
+    }
+}
+
+/// Works out what applying the manifests would do, without changing any container.
+/// `recreate` has to be the same as for the deployment that is planned.
+async fn plan_directory(
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+    recreate: bool,
+) -> Result<Vec<PlanEntry>> {
+    let found_manifest_paths = find_manifests(filter)?;
+    let mut _client = get_client(&settings.socket, RetryTimes::Never).await?;
+    let _r = tonic::Request::new(kanto::ListContainersRequest {});
+    let containers_list = _client.list(_r).await?.into_inner().containers;
+
+    let mut plan = Vec::with_capacity(found_manifest_paths.len());
+    for path in found_manifest_paths {
+        let parsed = match tokio::fs::read_to_string(&path).await {
+            Ok(container_str) => parse_manifest(&path, &container_str, &settings.variables),
+            Err(e) => Err(e.into()),
+        };
+        match parsed {
+            Ok(manifests) => {
+                for m in manifests {
+                    plan.push(plan_entry(
+                        &path,
+                        m.container,
+                        &containers_list,
+                        settings,
+                        recreate,
+                    ));
+                }
+            }
+            Err(e) => plan.push(PlanEntry {
+                manifest: path,
+                container: None,
+                action: PlanAction::Invalid,
+                changes: Vec::new(),
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+    Ok(plan)
+}
+
+fn container_status(name: &str, deployment: &DeployResult) -> ContainerStatus {
+    match deployment {
+        Ok(d) => ContainerStatus {
+            name: d.name.clone(),
+            id: Some(d.id.clone()),
+            action: d.action.into(),
+            error: None,
+        },
+        Err(e) => ContainerStatus {
+            name: String::from(name),
+            id: None,
+            action: LastAction::Failed,
+            error: Some(format!("{:#}", e)),
+        },
     }
+}
+
+/// Deploys every manifest in the directory. Existing containers that differ from their manifest
+/// are only recreated with `recreate`, otherwise they are just started if needed.
+async fn deploy_directory(
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+    deployed: &DeployedManifests,
+    status: &StatusStore,
+    recreate: bool,
+) -> Result<()> {
+    // Otherwise reported through the status listeners
+    let found_manifest_paths = find_manifests(filter).map_err(|e| {
+        systemd::status(&e.to_string());
+        e
+    })?;
 
-    let deployments = future::join_all(
+    // All manifests are parsed upfront, so that the dependencies between them
+    // are known before deploying anything
+    let files = future::join_all(
         found_manifest_paths
             .iter()
-            .map(|p| deploy(socket, retries, p, false)),
+            .map(|p| read_manifest(p, &settings.variables)),
     )
     .await;
 
+    // A manifest file may describe several containers, remember which file each one came from
+    let mut sources: Vec<(usize, Option<String>)> = Vec::new();
+    let mut manifests = Vec::new();
+    let mut hashes = Vec::with_capacity(files.len());
+    let mut errors = Vec::with_capacity(files.len());
+    for (idx, file) in files.into_iter().enumerate() {
+        hashes.push(file.content_hash);
+        match file.parsed {
+            Ok(parsed) => {
+                errors.push(None);
+                for m in parsed {
+                    sources.push((idx, Some(m.container.name.clone())));
+                    manifests.push(Ok((found_manifest_paths[idx].clone(), m)));
+                }
+            }
+            Err(e) => {
+                errors.push(Some(format!("{:#}", e)));
+                sources.push((idx, None));
+                manifests.push(Err(e));
+            }
+        }
+    }
+    let deployments = deploy_manifests(settings, manifests, recreate).await;
+
+    let mut containers: Vec<Vec<ContainerStatus>> = vec![Vec::new(); found_manifest_paths.len()];
+    {
+        let mut deployed = deployed.lock().unwrap();
+        for ((idx, name), deployment) in sources.iter().zip(deployments.iter()) {
+            if let Ok(d) = deployment {
+                if d.action != PlanAction::Skip {
+                    settings.ownership.record(
+                        &d.name,
+                        &d.id,
+                        &found_manifest_paths[*idx],
+                        hashes[*idx].clone(),
+                        d.stop.clone(),
+                    );
+                }
+                // The whole directory is deployed again on SIGHUP
+                let names = deployed
+                    .entry(found_manifest_paths[*idx].clone())
+                    .or_default();
+                if !names.contains(&d.name) {
+                    names.push(d.name.clone());
+                }
+            }
+            if let Some(name) = name {
+                containers[*idx].push(container_status(name, deployment));
+            }
+        }
+    }
+    status.retain(&found_manifest_paths);
+    for (((path, hash), error), containers) in found_manifest_paths
+        .iter()
+        .zip(hashes)
+        .zip(errors)
+        .zip(containers)
+    {
+        status.record(path, hash, containers, error);
+    }
+    status.save();
+
     let (successful, failed): (Vec<_>, Vec<_>) = deployments.into_iter().partition(Result::is_ok);
 
     log::debug!(
         "Successfully deployed {}, Failed: {}, Out of {}",
         successful.len(),
         failed.len(),
-        found_manifest_paths.len()
+        sources.len()
     );
 
     failed
@@ -308,79 +1226,860 @@ async fn deploy_directory(directory_path: &str, socket: &str, retries: RetryTime
 }
 
 #[cfg(feature = "filewatcher")]
-async fn redeploy_on_change(event: fs_watcher::Event, socket: &str) {
-    // In daemon mode we wait until a connection is available to proceed
-    // Unwrapping in this case is safe.
-    for path in &event.paths {
-        if !is_filetype(path, "json") {
+async fn undeploy_manifest(
+    path: &Path,
+    settings: &DeploySettings,
+    deployed: &DeployedManifests,
+    status: &StatusStore,
+) {
+    // The manifest directory is the source of truth, so a deleted manifest
+    // means its containers should no longer exist on the device
+    let removed = deployed.lock().unwrap().remove(path);
+    let mut containers = Vec::new();
+    for name in removed.unwrap_or_default() {
+        log::info!("Manifest {:?} was removed, cleaning-up [{}]", path, name);
+        containers.push(undeploy_status(settings, name).await);
+    }
+    status.record_removed(path, containers);
+    status.save();
+}
+
+/// Undeploys the container and reports how that went
+async fn undeploy_status(settings: &DeploySettings, name: String) -> ContainerStatus {
+    let error = match undeploy(settings, &name).await {
+        Ok(()) => None,
+        Err(e) => {
+            log::error!("[CM error] {:?}", e.root_cause());
+            Some(format!("{:#}", e))
+        }
+    };
+    ContainerStatus {
+        name,
+        id: None,
+        action: if error.is_some() {
+            LastAction::Failed
+        } else {
+            LastAction::Removed
+        },
+        error,
+    }
+}
+
+/// Finds the containers created by the auto-deployer from a manifest in the directory, that no
+/// manifest describes anymore. The containers of manifests that can not be read or parsed are
+/// kept, as it is unknown whether they are still described.
+async fn find_orphans(
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+) -> Result<Vec<PlanEntry>> {
+    // An empty directory is more likely a mistake (e.g. a missing mount) than every app dropped
+    let found_manifest_paths = find_manifests(filter)?;
+    let files = future::join_all(
+        found_manifest_paths
+            .iter()
+            .map(|p| read_manifest(p, &settings.variables)),
+    )
+    .await;
+    let mut described = HashSet::new();
+    let mut unreadable = HashSet::new();
+    for (path, file) in found_manifest_paths.iter().zip(files) {
+        match file.parsed {
+            Ok(manifests) => described.extend(manifests.into_iter().map(|m| m.container.name)),
+            Err(_) => {
+                unreadable.insert(path.clone());
+            }
+        }
+    }
+
+    let mut _client = get_client(&settings.socket, settings.retries).await?;
+    let _r = tonic::Request::new(kanto::ListContainersRequest {});
+    let containers_list = _client.list(_r).await?.into_inner().containers;
+
+    let mut orphans = Vec::new();
+    for (name, owned) in settings.ownership.owned() {
+        // Deployed from a desired state, or from a manifest left out by --include or --exclude
+        if !filter.matches(&owned.manifest) || described.contains(&name) {
+            continue;
+        }
+        if unreadable.contains(&owned.manifest) {
+            log::warn!(
+                "Not pruning [{}], its manifest {:?} can not be read",
+                name,
+                owned.manifest
+            );
             continue;
         }
-        if event.kind.is_create() || event.kind.is_modify() {
-            if let Err(e) = deploy(socket, RetryTimes::Forever, path, true).await {
+        match containers_list.iter().find(|c| c.name == name) {
+            None => {
+                log::debug!("[{}] was already removed", name);
+                settings.ownership.forget(&name);
+            }
+            Some(c) if c.id != owned.id && !settings.force => log::info!(
+                "Not pruning [{}], it was not created by the auto-deployer (see --force)",
+                name
+            ),
+            Some(_) => orphans.push(PlanEntry {
+                manifest: owned.manifest,
+                container: Some(name),
+                action: PlanAction::Prune,
+                changes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+    Ok(orphans)
+}
+
+/// Asks on the terminal, anything but "y" or "yes" is a no
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "Can not ask for confirmation without a terminal, pass --yes"
+        ));
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Stops and removes the containers found by [`find_orphans`], after confirmation unless `yes`
+async fn prune(
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+    status: &StatusStore,
+    yes: bool,
+) -> Result<()> {
+    let orphans = find_orphans(filter, settings).await?;
+    if orphans.is_empty() {
+        log::info!("Nothing to prune");
+        return Ok(());
+    }
+    if !yes {
+        deploy_plan::print_plan(&orphans, PlanFormat::Text)?;
+        if !confirm(&format!("Remove {} container(s)?", orphans.len()))? {
+            println!("Nothing removed");
+            return Ok(());
+        }
+    }
+
+    let mut removed: HashMap<PathBuf, Vec<ContainerStatus>> = HashMap::new();
+    for orphan in orphans {
+        if signals::shutting_down() {
+            break;
+        }
+        let name = orphan.container.unwrap_or_default();
+        log::info!(
+            "Pruning [{}], {:?} no longer describes it",
+            name,
+            orphan.manifest
+        );
+        removed
+            .entry(orphan.manifest)
+            .or_default()
+            .push(undeploy_status(settings, name).await);
+    }
+    let mut failed = 0;
+    for (manifest, containers) in removed {
+        failed += containers
+            .iter()
+            .filter(|c| c.action == LastAction::Failed)
+            .count();
+        // The status of a manifest that is still there is about the containers it describes
+        if !manifest.exists() {
+            status.record_removed(&manifest, containers);
+        }
+    }
+    status.save();
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "Could not prune {} container(s). Check the logs above for more information.",
+            failed
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "filewatcher")]
+async fn redeploy_manifest(
+    path: &Path,
+    settings: &DeploySettings,
+    deployed: &DeployedManifests,
+    status: &StatusStore,
+) {
+    let file = read_manifest(path, &settings.variables).await;
+    let manifests = match file.parsed {
+        Ok(m) => m,
+        Err(e) => {
+            log::error!("[CM error] {:?}", e.root_cause());
+            status.record(
+                path,
+                file.content_hash,
+                Vec::new(),
+                Some(format!("{:#}", e)),
+            );
+            status.save();
+            return;
+        }
+    };
+    let names: Vec<String> = manifests.iter().map(|m| m.container.name.clone()).collect();
+    let manifests = manifests
+        .into_iter()
+        .map(|m| Ok((path.to_path_buf(), m)))
+        .collect();
+    let deployments = deploy_manifests(settings, manifests, true).await;
+    let mut failed = false;
+    for deployment in &deployments {
+        match deployment {
+            Ok(d) if d.action == PlanAction::Skip => {}
+            Ok(d) => settings.ownership.record(
+                &d.name,
+                &d.id,
+                path,
+                file.content_hash.clone(),
+                d.stop.clone(),
+            ),
+            Err(e) => {
                 log::error!("[CM error] {:?}", e.root_cause());
+                failed = true;
+            }
+        }
+    }
+    let mut containers: Vec<ContainerStatus> = names
+        .iter()
+        .zip(deployments.iter())
+        .map(|(name, d)| container_status(name, d))
+        .collect();
+
+    let stale: Vec<String> = {
+        let mut deployed = deployed.lock().unwrap();
+        let previous = deployed
+            .insert(path.to_path_buf(), names.clone())
+            .unwrap_or_default();
+        let stale = previous.into_iter().filter(|n| !names.contains(n));
+        if failed {
+            // Keep the old containers around (and tracked) until the manifest deploys successfully
+            deployed.get_mut(path).unwrap().extend(stale);
+            Vec::new()
+        } else {
+            stale.collect()
+        }
+    };
+    // The containers are no longer in the manifest (e.g. renamed), clean them up
+    for old_name in stale {
+        log::info!(
+            "Container [{}] is no longer defined in {:?}",
+            old_name,
+            path
+        );
+        containers.push(undeploy_status(settings, old_name).await);
+    }
+    status.record(path, file.content_hash, containers, None);
+    status.save();
+}
+
+/// Recreates the missing containers of the manifests and starts the stopped ones, e.g. after
+/// they were removed or stopped with kanto-cm. Every correction is logged as drift.
+#[cfg(feature = "filewatcher")]
+async fn reconcile(
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+    deployed: &DeployedManifests,
+    status: &StatusStore,
+) {
+    if signals::shutting_down() {
+        return;
+    }
+    let found_manifest_paths = match filter.find(filter.root()) {
+        Ok(paths) => paths,
+        Err(e) => {
+            log::warn!("Could not check for drift: {}", e);
+            return;
+        }
+    };
+    let containers_list = match get_client(&settings.socket, RetryTimes::Never).await {
+        Ok(mut client) => {
+            let _r = tonic::Request::new(kanto::ListContainersRequest {});
+            client.list(_r).await.map(|r| r.into_inner().containers)
+        }
+        Err(e) => {
+            log::warn!("Could not check for drift: {}", e);
+            return;
+        }
+    };
+    let containers_list = match containers_list {
+        Ok(list) => list,
+        Err(e) => {
+            log::warn!("Could not check for drift: {}", e);
+            return;
+        }
+    };
+    let files = future::join_all(
+        found_manifest_paths
+            .iter()
+            .map(|p| read_manifest(p, &settings.variables)),
+    )
+    .await;
+
+    // Manifests that can not be parsed are reported when they change, not on every check
+    let files: Vec<(&PathBuf, Option<String>, Vec<manifest_parser::Manifest>)> =
+        found_manifest_paths
+            .iter()
+            .zip(files)
+            .filter_map(|(path, file)| Some((path, file.content_hash, file.parsed.ok()?)))
+            .collect();
+    let mut drifted = Vec::new();
+    for (path, _, manifests) in &files {
+        for m in manifests {
+            let name = &m.container.name;
+            match containers_list.iter().find(|c| &c.name == name) {
+                None => log::warn!("Drift: [{}] of {:?} is missing, recreating it", name, path),
+                Some(c) if !settings.ownership.owns(name, &c.id) && !settings.force => {
+                    log::debug!(
+                        "[{}] was not created by the auto-deployer, not checking it",
+                        name
+                    );
+                    continue;
+                }
+                Some(c) if !container_running(c) && !m.options.keep_stopped => {
+                    log::warn!("Drift: [{}] of {:?} is stopped, starting it", name, path)
+                }
+                Some(_) => continue,
+            }
+            drifted.push(name.clone());
+        }
+    }
+    if drifted.is_empty() {
+        log::debug!("No drift in {} manifest(s)", files.len());
+        return;
+    }
+
+    // Only what drifted is deployed, the other dependencies are in place
+    let manifests = files
+        .iter()
+        .flat_map(|(path, _, manifests)| manifests.iter().map(move |m| (*path, m)))
+        .filter(|(_, m)| drifted.contains(&m.container.name))
+        .map(|(path, m)| {
+            let mut m = m.clone();
+            m.options.depends_on.retain(|dep| drifted.contains(dep));
+            Ok((path.clone(), m))
+        })
+        .collect();
+    let deployments: HashMap<String, DeployResult> = drifted
+        .iter()
+        .cloned()
+        .zip(deploy_manifests(settings, manifests, false).await)
+        .collect();
+
+    for (path, content_hash, manifests) in files {
+        let mut containers = Vec::with_capacity(manifests.len());
+        let mut changed = false;
+        for m in manifests {
+            let name = m.container.name;
+            let container = match deployments.get(&name) {
+                Some(deployment) => {
+                    changed = true;
+                    match deployment {
+                        Ok(d) => {
+                            log::info!("Drift: {} [{}]", LastAction::from(d.action), name);
+                            settings.ownership.record(
+                                &name,
+                                &d.id,
+                                path,
+                                content_hash.clone(),
+                                d.stop.clone(),
+                            );
+                            let mut deployed = deployed.lock().unwrap();
+                            let names = deployed.entry(path.clone()).or_default();
+                            if !names.contains(&name) {
+                                names.push(name.clone());
+                            }
+                        }
+                        Err(e) => log::error!("Drift: could not correct [{}]: {:#}", name, e),
+                    }
+                    container_status(&name, deployment)
+                }
+                None => ContainerStatus {
+                    id: containers_list
+                        .iter()
+                        .find(|c| c.name == name)
+                        .map(|c| c.id.clone()),
+                    name,
+                    action: LastAction::Unchanged,
+                    error: None,
+                },
             };
+            containers.push(container);
+        }
+        if changed {
+            status.record(path, content_hash, containers, None);
         }
     }
+    status.save();
+}
+
+#[cfg(feature = "filewatcher")]
+async fn redeploy_on_change(
+    event: fs_watcher::Event,
+    filter: &ManifestFilter,
+    settings: &DeploySettings,
+    deployed: &DeployedManifests,
+    status: &StatusStore,
+) {
+    // In daemon mode we wait until a connection is available to proceed
+    // Unwrapping in this case is safe.
+    for path in &event.paths {
+        if event.kind.is_remove() {
+            // A removed directory takes all manifests below it along
+            let removed: Vec<PathBuf> = deployed
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|p| p.starts_with(path))
+                .cloned()
+                .collect();
+            if removed.is_empty() {
+                log::debug!("No deployed container known for {:?}", path);
+            }
+            for manifest_path in removed {
+                undeploy_manifest(&manifest_path, settings, deployed, status).await;
+            }
+        } else if event.kind.is_create() || event.kind.is_modify() {
+            if path.is_dir() {
+                // The manifests of a directory moved into place may not have events of their own
+                match filter.find(path) {
+                    Ok(found) => {
+                        for manifest_path in found {
+                            redeploy_manifest(&manifest_path, settings, deployed, status).await;
+                        }
+                    }
+                    Err(e) => log::error!("Could not read directory {:?}: {}", path, e),
+                }
+            } else if filter.matches(path) {
+                redeploy_manifest(path, settings, deployed, status).await;
+            }
+        }
+    }
+}
+
+/// What the deployments are based on, loaded again from the configuration on SIGHUP
+struct DeployConfig {
+    manifests_path: String,
+    filter: ManifestFilter,
+    settings: DeploySettings,
+}
+
+impl DeployConfig {
+    fn new(
+        cli: &CliArgs,
+        variables: manifest_parser::Variables,
+        ownership: Arc<ownership::Ownership>,
+        deploying: Arc<tokio::sync::Mutex<()>>,
+        daemon: bool,
+    ) -> Result<Self> {
+        let canonical_manifests_path = std::fs::canonicalize(&cli.manifests_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Could not expand path {:#?}, err: {}",
+                &cli.manifests_path,
+                e
+            )
+        })?;
+        let filter = ManifestFilter::new(&canonical_manifests_path, &cli.include, &cli.exclude)?;
+
+        // Do not retry by default (CLI tool), but retry forever when running as daemon
+        let retries = match cli.retries {
+            Some(retries) => retries,
+            None if daemon => RetryTimes::Forever,
+            None => RetryTimes::Never,
+        };
+        let settings = DeploySettings {
+            socket: String::from(cli.socket_cm.to_string_lossy()),
+            retries,
+            readiness: cli.wait_ready.then(|| readiness::ReadinessCheck {
+                settle_time: Duration::from_secs(cli.ready_settle_secs),
+                timeout: Duration::from_secs(cli.ready_timeout_secs),
+            }),
+            variables,
+            ownership,
+            force: cli.force,
+            adopt: cli.adopt,
+            stop: kanto_cnt::StopOptions {
+                timeout: cli.stop_timeout_secs,
+                force: cli.stop_force,
+                signal: cli.stop_signal.clone(),
+            },
+            deploying,
+        };
+        Ok(DeployConfig {
+            manifests_path: String::from(canonical_manifests_path.to_string_lossy()),
+            filter,
+            settings,
+        })
+    }
+}
+
+/// Reads the configuration file and the variables file again. Daemon mode, the MQTT settings,
+/// the status file and the log level are only taken at startup.
+#[cfg(feature = "filewatcher")]
+fn reload_config(settings: &DeploySettings, daemon: bool) -> Result<(CliArgs, DeployConfig)> {
+    let cli = kad_config::reload()?;
+    let variables = manifest_parser::Variables::load(cli.vars_file.as_deref())
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let config = DeployConfig::new(
+        &cli,
+        variables,
+        settings.ownership.clone(),
+        settings.deploying.clone(),
+        daemon,
+    )?;
+    Ok((cli, config))
+}
+
+#[cfg(feature = "filewatcher")]
+fn watch_config(cli: &CliArgs) -> fs_watcher::WatchConfig {
+    fs_watcher::WatchConfig {
+        backend: cli.watch_backend,
+        debounce: Duration::from_millis(cli.debounce_ms),
+        poll_interval: Duration::from_secs_f64(cli.poll_interval_secs),
+        reconcile_interval: cli
+            .reconcile_interval_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        watchdog: systemd::watchdog_interval(),
+    }
+}
+
+/// With a command topic to re-enable it, KAD keeps running after VUM took over, but does not
+/// deploy anything until it is enabled again
+#[cfg(feature = "mqtt")]
+fn deployments_paused(cli: &CliArgs) -> bool {
+    cli.mqtt.enabled
+        && cli.mqtt.command_topic.is_some()
+        && kad_lock::lock_state(&cli.lock_path) == kad_lock::LockState::Disabled
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn deployments_paused(_cli: &CliArgs) -> bool {
+    false
+}
+
+/// Gives the MQTT threads the time to complete the desired state being applied, if any
+#[cfg(feature = "mqtt")]
+async fn join_threads(threads: Vec<thread::JoinHandle<()>>) {
+    let deadline = tokio::time::Instant::now() + THREAD_SHUTDOWN_TIMEOUT;
+    while threads.iter().any(|t| !t.is_finished()) {
+        if tokio::time::Instant::now() >= deadline {
+            log::warn!("Timed out waiting for the MQTT clients to stop");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init_from_env(
+    let cli = match kad_config::parse() {
+        Ok(cli) => Arc::new(cli),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+    };
+    let mut logger = env_logger::Builder::from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
-
-    let cli = Arc::new(CliArgs::parse());
+    if let Some(level) = &cli.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
     log::debug!("{:#?}", cli);
 
-    let socket_path = String::from(cli.socket_cm.to_string_lossy());
-    let canonical_manifests_path = match std::fs::canonicalize(&cli.manifests_path) {
-        Ok(p) => p,
+    let variables = match manifest_parser::Variables::load(cli.vars_file.as_deref()) {
+        Ok(v) => v,
         Err(e) => {
-            log::error!(
-                "Could not expand path {:#?}, err: {}",
-                &cli.manifests_path,
-                e
-            );
+            log::error!("{}", e);
+            std::process::exit(-1);
+        }
+    };
+
+    match &cli.command {
+        Some(Command::Validate { path }) => {
+            let path = path.as_ref().unwrap_or(&cli.manifests_path);
+            match validate_manifests(path, &variables, &cli.include, &cli.exclude) {
+                Ok(true) => return Ok(()),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    log::error!("Could not validate {:#?}: {}", path, e);
+                    std::process::exit(-1);
+                }
+            }
+        }
+        Some(Command::Status { format }) => {
+            #[cfg(feature = "mqtt")]
+            let lock = Some(status_report::LockStatus {
+                path: cli.lock_path.clone(),
+                state: kad_lock::lock_state(&cli.lock_path).to_string(),
+            });
+            #[cfg(not(feature = "mqtt"))]
+            let lock = None;
+            if let Err(e) = status_report::print_status(&cli.status_file, *format, lock) {
+                log::error!("{}", e);
+                std::process::exit(-1);
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "mqtt")]
+        Some(Command::Enable) => {
+            match kad_lock::enable(&cli.lock_path, "command line") {
+                Ok(true) => println!("Enabled ({:?})", cli.lock_path),
+                Ok(false) => println!("Already enabled ({:?})", cli.lock_path),
+                Err(e) => {
+                    log::error!("Could not enable: {}", e);
+                    std::process::exit(-1);
+                }
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "mqtt")]
+        Some(Command::Disable) => {
+            match kad_lock::disable(&cli.lock_path, "command line") {
+                Ok(true) => println!("Disabled ({:?})", cli.lock_path),
+                Ok(false) => println!("Not enabled ({:?})", cli.lock_path),
+                Err(e) => {
+                    log::error!("Could not disable: {}", e);
+                    std::process::exit(-1);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Prune { .. }) | None => {}
+    }
+
+    #[cfg(feature = "filewatcher")]
+    let daemon = cli.daemon;
+    #[cfg(not(feature = "filewatcher"))]
+    let daemon = false;
+    let ownership = Arc::new(ownership::Ownership::open(&ownership::ownership_file(
+        &cli.status_file,
+    )));
+    let deploying = Arc::new(tokio::sync::Mutex::new(()));
+    let config = match DeployConfig::new(&cli, variables, ownership, deploying, daemon) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("{}", e);
             std::process::exit(-1);
         }
     };
-    let manifests_path = String::from(canonical_manifests_path.to_string_lossy());
 
-    log::info!("Running initial deployment of {:#?}", manifests_path);
+    if let Some(Command::Prune { yes }) = cli.command {
+        let result = if cli.dry_run {
+            find_orphans(&config.filter, &config.settings)
+                .await
+                .and_then(|orphans| deploy_plan::print_plan(&orphans, cli.plan_format))
+        } else {
+            let status = StatusStore::open(&cli.status_file);
+            prune(&config.filter, &config.settings, &status, yes).await
+        };
+        if let Err(e) = result {
+            log::error!("Could not prune: {:#}", e);
+            std::process::exit(-1);
+        }
+        return Ok(());
+    }
 
-    // Do not retry by default (CLI tool)
-    let mut retry_times = RetryTimes::Never;
+    if cli.dry_run {
+        log::info!("Planning deployment of {:#?}", config.manifests_path);
+        // The initial deployment does not recreate containers
+        let mut plan = plan_directory(&config.filter, &config.settings, false).await?;
+        if cli.prune {
+            plan.extend(find_orphans(&config.filter, &config.settings).await?);
+        }
+        deploy_plan::print_plan(&plan, cli.plan_format)?;
+        return Ok(());
+    }
 
+    // From here on, SIGTERM and SIGINT let the deployments in flight complete
     #[cfg(feature = "filewatcher")]
-    if cli.daemon {
-        // If compiled with filewatcher and running as daemon, retry forever
-        retry_times = RetryTimes::Forever
+    let mut signals = signals::listen()?;
+    #[cfg(not(feature = "filewatcher"))]
+    signals::listen()?;
+
+    log::info!("Running initial deployment of {:#?}", config.manifests_path);
+
+    let deployed = DeployedManifests::default();
+    let mut status = StatusStore::open(&cli.status_file);
+    status.add_listener(Arc::new(systemd::StatusNotifier));
+    #[cfg(feature = "mqtt")]
+    let event_publisher = match &cli.mqtt.events_topic {
+        Some(topic) => match mqtt_publisher::EventPublisher::start(&cli.mqtt, topic) {
+            Ok(publisher) => {
+                status.add_listener(publisher.clone());
+                Some(publisher)
+            }
+            Err(e) => {
+                log::error!("Not publishing deployment events: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(feature = "mqtt")]
+    if cli.mqtt.desired_state && !cli.daemon {
+        log::warn!("Desired states are only applied in daemon mode (--daemon)");
+    }
+    #[cfg(feature = "mqtt")]
+    if cli.mqtt.desired_state
+        && cli.mqtt.enabled
+        && cli.mqtt.topic == cli.mqtt.desired_state_feedback_topic
+    {
+        log::warn!("--mqtt-topic is the desired state feedback topic, the auto-deployer will disable itself on its own feedback");
     }
 
     // One-shot deployment of all manifests in directory
-    if let Err(e) = deploy_directory(&manifests_path, &socket_path, retry_times).await {
-        log::error!("Failed to deploy directory: {e}")
+    let paused = deployments_paused(&cli);
+    if paused {
+        log::warn!("KAD is disabled, not deploying until it is enabled again");
+    } else {
+        systemd::status("Running initial deployment");
+        if let Err(e) =
+            deploy_directory(&config.filter, &config.settings, &deployed, &status, false).await
+        {
+            log::error!("Failed to deploy directory: {e}");
+        }
+    }
+    if cli.prune && !paused && !signals::shutting_down() {
+        if let Err(e) = prune(&config.filter, &config.settings, &status, true).await {
+            log::error!("Could not prune: {:#}", e);
+        }
+    }
+    // systemd would take READY after STOPPING as the service being up again
+    if !signals::shutting_down() {
+        systemd::ready();
     }
 
     #[cfg(feature = "filewatcher")]
-    if cli.daemon {
+    if cli.daemon && !signals::shutting_down() {
         static THREAD_TERMINATE_FLAG: AtomicBool = AtomicBool::new(false);
         #[cfg(feature = "mqtt")]
+        let mut threads = Vec::new();
+        #[cfg(feature = "mqtt")]
         if cli.mqtt.enabled {
-            thread::spawn({
+            threads.push(thread::spawn({
                 let cli = cli.clone();
-                || mqtt_listener::mqtt_main(cli, &THREAD_TERMINATE_FLAG)
-            });
+                || {
+                    if let Err(e) = mqtt_listener::mqtt_main(cli, &THREAD_TERMINATE_FLAG) {
+                        log::error!("MQTT listener stopped: {e}");
+                    }
+                }
+            }));
         }
-        log::info!(
-            "Running in daemon mode. Continuously monitoring {:#?}",
-            manifests_path
-        );
-        fs_watcher::async_watch(&THREAD_TERMINATE_FLAG, &manifests_path, |e| async {
-            redeploy_on_change(e, &socket_path).await
-        })
-        .await?
+        #[cfg(feature = "mqtt")]
+        if cli.mqtt.desired_state {
+            threads.push(thread::spawn({
+                let cli = cli.clone();
+                let settings = config.settings.clone();
+                move || {
+                    if let Err(e) = desired_state_agent::agent_main(cli, settings) {
+                        log::error!("Not applying desired states: {e}");
+                    }
+                }
+            }));
+        }
+
+        let mut config = config;
+        let mut watch_config = watch_config(&cli);
+        loop {
+            log::info!(
+                "Running in daemon mode. Continuously monitoring {:#?}",
+                config.manifests_path
+            );
+            let exit = fs_watcher::async_watch(
+                &THREAD_TERMINATE_FLAG,
+                &mut signals,
+                &config.manifests_path,
+                watch_config,
+                |e| async {
+                    if deployments_paused(&cli) {
+                        log::debug!("KAD is disabled, not deploying");
+                        return;
+                    }
+                    let _deploying = config.settings.deploying.lock().await;
+                    match e {
+                        fs_watcher::WatchEvent::Changed(e) => {
+                            redeploy_on_change(
+                                e,
+                                &config.filter,
+                                &config.settings,
+                                &deployed,
+                                &status,
+                            )
+                            .await
+                        }
+                        fs_watcher::WatchEvent::Reconcile => {
+                            reconcile(&config.filter, &config.settings, &deployed, &status).await
+                        }
+                    }
+                },
+            )
+            .await?;
+            match exit {
+                fs_watcher::WatchExit::Signal(signals::DaemonSignal::Reload) => {
+                    systemd::reloading();
+                    match reload_config(&config.settings, daemon) {
+                        Ok((new_cli, new_config)) => {
+                            log::info!("Reloaded the configuration");
+                            watch_config = self::watch_config(&new_cli);
+                            config = new_config;
+                        }
+                        Err(e) => log::error!(
+                            "Could not reload the configuration, keeping the current one: {:#}",
+                            e
+                        ),
+                    }
+                    if deployments_paused(&cli) {
+                        log::warn!(
+                            "KAD is disabled, not redeploying {:#?}",
+                            config.manifests_path
+                        );
+                    } else {
+                        log::info!("Redeploying {:#?}", config.manifests_path);
+                        let _deploying = config.settings.deploying.lock().await;
+                        if let Err(e) = deploy_directory(
+                            &config.filter,
+                            &config.settings,
+                            &deployed,
+                            &status,
+                            true,
+                        )
+                        .await
+                        {
+                            log::error!("Failed to deploy directory: {e}");
+                        }
+                    }
+                    if !signals::shutting_down() {
+                        systemd::ready();
+                    }
+                }
+                fs_watcher::WatchExit::Signal(signals::DaemonSignal::Shutdown)
+                | fs_watcher::WatchExit::Terminated => break,
+            }
+        }
+
+        #[cfg(feature = "mqtt")]
+        if signals::shutting_down() {
+            signals::run_shutdown_hooks();
+            join_threads(threads).await;
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(publisher) = event_publisher {
+        publisher.close();
     }
 
+    if signals::shutting_down() {
+        log::info!("Shut down");
+    }
     Ok(())
 }
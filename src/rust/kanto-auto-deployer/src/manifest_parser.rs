@@ -13,18 +13,301 @@
 
 //! A module for parsing-out container manifests.
 //!
-//! The only public API is the `try_parse_manifest` function that takes a json string
-//! read-out from disk and tries to parse it to the "internal container state representation"
+//! The only public API is the `try_parse_manifest` function that takes a manifest
+//! read-out from disk (JSON, YAML or TOML, see `ManifestSyntax`) and tries to parse it to the "internal container state representation"
 //! for Kanto-CM.
 //!
 //! If the json is already in the internal state representation it would be parsed out directly.
 //! Otherwise an "initdir" style manifest will be assumed and an automatic conversion will be attempted
 //! by first expanding-out the manifest (since init-dir style manifests allow missing keys) and re-mapping it
 //! to the internal state representation.
+//!
+//! Both formats may additionally carry auto-deployer specific options under the
+//! `auto_deployer` key, e.g. `"auto_deployer": {"depends_on": ["databroker"]}`.
+//! These are never sent to Kanto-CM.
+//!
+//! Kubernetes `Pod` and `Deployment` objects and docker-compose files are converted as well
+//! (see the `kubernetes` and `compose` modules), one manifest per container of the pod or compose service.
+//!
+//! Before any parsing, `${VAR}` and `${VAR:-default}` references in string values are replaced
+//! with the values from `Variables` (see `substitute_variables`).
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use crate::containers::github::com::eclipse_kanto::container_management::containerm::api::types::containers::Container;
 use json_patch::merge;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub(crate) mod compose;
+pub(crate) mod conversion;
+#[cfg(feature = "mqtt")]
+pub(crate) mod desired_state;
+pub(crate) mod kubernetes;
+
+pub(crate) static DEPLOYMENT_OPTIONS_KEY: &str = "auto_deployer";
+
+/// Options that control how the auto-deployer handles a manifest
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct DeploymentOptions {
+    /// Names of the containers that have to be deployed before this one
+    pub depends_on: Vec<String>,
+    /// Do not start the container again when it is found stopped by the periodic reconciliation
+    /// (e.g. a job that runs once after each deployment)
+    pub keep_stopped: bool,
+    /// How the container is stopped before it is recreated or removed
+    pub stop: StopSettings,
+}
+
+/// Values for the Kanto-CM stop options, the unset ones are taken from the `--stop-*` options
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct StopSettings {
+    /// Signal sent to the container, e.g. "SIGINT"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// Seconds the container is given to stop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i64>,
+    /// Kill the container if it has not stopped within the timeout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub container: Container,
+    pub options: DeploymentOptions,
+}
+
+/// Languages a manifest can be written in, selected by its file extension.
+/// All of them are read into the JSON data model, so the conversion and template
+/// merging below are the same for every syntax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestSyntax {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ManifestSyntax {
+    /// File extensions recognized as manifests
+    pub const EXTENSIONS: [&'static str; 4] = ["json", "yaml", "yml", "toml"];
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(ManifestSyntax::Json),
+            "yaml" | "yml" => Some(ManifestSyntax::Yaml),
+            "toml" => Some(ManifestSyntax::Toml),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ManifestSyntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestSyntax::Json => write!(f, "JSON"),
+            ManifestSyntax::Yaml => write!(f, "YAML"),
+            ManifestSyntax::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+/// Reads a manifest document into the JSON data model
+pub fn parse_document(
+    content: &str,
+    syntax: ManifestSyntax,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let document = match syntax {
+        ManifestSyntax::Json => serde_json::from_str(content)?,
+        ManifestSyntax::Yaml => serde_yaml::from_str(content)?,
+        ManifestSyntax::Toml => toml::from_str(content)?,
+    };
+    Ok(document)
+}
+
+/// Values for the `${VAR}` references in manifests
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    values: HashMap<String, String>,
+}
+
+impl Variables {
+    /// Reads the variables from the process environment and the key/value file (if any).
+    /// The file takes precedence, so that an unrelated environment variable that happens to
+    /// have the same name does not change what the file sets.
+    pub fn load(vars_file: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut values: HashMap<String, String> = std::env::vars().collect();
+        if let Some(path) = vars_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Could not read variables file {:?}: {}", path, e))?;
+            values.extend(parse_vars_file(&content).map_err(|e| anyhow!("In {:?}: {}", path, e))?);
+        }
+        Ok(Variables { values })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// Parses `KEY=VALUE` lines. Empty lines and lines starting with `#` are skipped,
+/// values may be wrapped in single or double quotes.
+fn parse_vars_file(content: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut values = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected KEY=VALUE", i + 1))?;
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(value);
+        values.insert(String::from(key.trim()), String::from(unquoted));
+    }
+    Ok(values)
+}
+
+fn is_valid_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expands `${VAR}`, `${VAR-default}` and `${VAR:-default}` (default also used for empty values)
+/// in one string, as well as `${VAR?message}` and `${VAR:?message}`, which fail with the message
+/// instead. `$${` stands for a literal `${`. Names of undefined variables without a default are collected.
+fn substitute_str(
+    input: &str,
+    vars: &Variables,
+    undefined: &mut Vec<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let reference = match after.strip_prefix('{') {
+            Some(r) => r,
+            None => {
+                out.push('$');
+                rest = after;
+                continue;
+            }
+        };
+        let end = reference
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated variable reference in \"{}\"", input))?;
+        let body = &reference[..end];
+        let name_end = body
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(body.len());
+        let (name, modifier) = body.split_at(name_end);
+        if !is_valid_variable_name(name) {
+            return Err(anyhow!("Invalid variable name \"{}\" in \"{}\"", name, input).into());
+        }
+        // The `:` forms treat an empty value like an unset one
+        let (value, operator) = match modifier.strip_prefix(':') {
+            Some(operator) => (vars.get(name).filter(|v| !v.is_empty()), operator),
+            None => (vars.get(name), modifier),
+        };
+        match (operator.chars().next(), value) {
+            (None, Some(value)) if modifier.is_empty() => out.push_str(value),
+            (None, None) if modifier.is_empty() => undefined.push(String::from(name)),
+            (Some('-') | Some('?'), Some(value)) => out.push_str(value),
+            (Some('-'), None) => out.push_str(&operator[1..]),
+            (Some('?'), None) if operator.len() == 1 => {
+                return Err(anyhow!("Variable \"{}\" is required", name).into())
+            }
+            (Some('?'), None) => {
+                return Err(anyhow!("Variable \"{}\" is required: {}", name, &operator[1..]).into())
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Invalid variable reference \"${{{}}}\" in \"{}\"",
+                    body,
+                    input
+                )
+                .into())
+            }
+        }
+        rest = &reference[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn substitute_value(
+    value: &mut Value,
+    vars: &Variables,
+    undefined: &mut Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match value {
+        Value::String(s) => *s = substitute_str(s, vars, undefined)?,
+        Value::Array(items) => {
+            for item in items {
+                substitute_value(item, vars, undefined)?;
+            }
+        }
+        Value::Object(fields) => {
+            for field in fields.values_mut() {
+                substitute_value(field, vars, undefined)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces the variable references in all string values of the manifest (keys, numbers and
+/// booleans are left as-is)
+/// and returns the names of the undefined variables, sorted and without duplicates.
+pub(crate) fn substitute_collecting_undefined(
+    manifest: &mut Value,
+    vars: &Variables,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut undefined = Vec::new();
+    substitute_value(manifest, vars, &mut undefined)?;
+    undefined.sort();
+    undefined.dedup();
+    Ok(undefined)
+}
+
+/// Replaces the variable references in all string values of the manifest.
+/// Any undefined variable without a default is an error.
+pub fn substitute_variables(
+    manifest: &mut Value,
+    vars: &Variables,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let undefined = substitute_collecting_undefined(manifest, vars)?;
+    if !undefined.is_empty() {
+        let names: Vec<String> = undefined.iter().map(|n| format!("${{{}}}", n)).collect();
+        return Err(anyhow!("Undefined variable(s) {}", names.join(", ")).into());
+    }
+    Ok(())
+}
+
+/// Joins key path segments for printing, e.g. `host_config.port_mappings[0].proto`
+pub(crate) fn display_key_path(path: &[String]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        if !out.is_empty() && !segment.starts_with('[') {
+            out.push('.');
+        }
+        out.push_str(segment);
+    }
+    out
+}
 
 /// Takes a key from a "template" and a "data" dictionary and replaces
 /// the template's value for that key from the the data dict.
@@ -53,81 +336,140 @@ fn try_or_print<T: std::fmt::Debug>(res: Result<T, Box<dyn std::error::Error>>)
     }
 }
 
+/// Init-dir keys that have to be present, with the internal representation keys they map to
+pub(crate) static MANDATORY_INIT_DIR_KEYS: [(&str, &str); 2] =
+    [("container_name", "name"), ("image", "image")];
+
+/// Init-dir keys that fall back to the template defaults when missing,
+/// with the internal representation keys they map to
+pub(crate) static OPTIONAL_INIT_DIR_KEYS: [(&str, &str); 6] = [
+    ("container_id", "id"),
+    ("domain_name", "domain_name"),
+    ("host_name", "host_name"),
+    ("mount_points", "mounts"),
+    ("config", "config"),
+    ("host_config", "host_config"),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ManifestFormat {
+    /// Kanto-CM internal container state representation
+    Internal,
+    /// Kanto container-config ("init-dir") style manifest
+    InitDir,
+    /// Kubernetes Pod or Deployment
+    Kubernetes,
+    /// docker-compose file
+    Compose,
+}
+
+impl std::fmt::Display for ManifestFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestFormat::Internal => write!(f, "internal state representation"),
+            ManifestFormat::InitDir => write!(f, "init-dir container config"),
+            ManifestFormat::Kubernetes => write!(f, "Kubernetes"),
+            ManifestFormat::Compose => write!(f, "docker-compose"),
+        }
+    }
+}
+
+/// Guesses the manifest format from its keys, without trying to parse it.
+pub fn detect_format(manifest: &Value) -> Option<ManifestFormat> {
+    if kubernetes::is_kubernetes(manifest) {
+        return Some(ManifestFormat::Kubernetes);
+    }
+    if compose::is_compose(manifest) {
+        return Some(ManifestFormat::Compose);
+    }
+    let manifest = manifest.as_object()?;
+    if manifest.contains_key("container_name") {
+        Some(ManifestFormat::InitDir)
+    } else if manifest.contains_key("name") {
+        Some(ManifestFormat::Internal)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn internal_state_template() -> Result<Value, Box<dyn std::error::Error>> {
+    let int_state_repr = include_str!("kanto_internal_ctr_repr.json.template.in");
+    Ok(serde_json::from_str(int_state_repr)?)
+}
+
 /// Tries to map top-level json properties from kanto container-config style manifests
 /// (ref: <https://websites.eclipseprojects.io/kanto/docs/references/containers/container-config/#template>)
 /// to kanto internal container state representation by directly cloning their values
 /// (check src/kanto_internal_ctr_repr.json.template.in)
-fn map_to_internal_state_manifest(
+pub(crate) fn map_to_internal_state_manifest(
     container_manifest: Value,
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let int_state_repr = include_str!("kanto_internal_ctr_repr.json.template.in");
-    let mut int_state_repr = serde_json::from_str(int_state_repr)?;
+    let mut int_state_repr = serde_json::from_value(internal_state_template()?)?;
     let ctr_manifest: Map<String, Value> = serde_json::from_value(container_manifest)?;
 
     // These fields are considered mandatory, if they do not exist,
     // fail the manifest re-parsing
-    update_template(&mut int_state_repr, &ctr_manifest, "name", "container_name")?;
-    update_template(&mut int_state_repr, &ctr_manifest, "image", "image")?;
+    for (init_dir_key, internal_key) in MANDATORY_INIT_DIR_KEYS {
+        update_template(
+            &mut int_state_repr,
+            &ctr_manifest,
+            internal_key,
+            init_dir_key,
+        )?;
+    }
     // These are considered as configuration options
     // If they are missing, print an error and continue with defaults
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "id",
-        "container_id",
-    ));
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "domain_name",
-        "domain_name",
-    ));
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "host_name",
-        "host_name",
-    ));
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "mounts",
-        "mount_points",
-    ));
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "config",
-        "config",
-    ));
-    try_or_print(update_template(
-        &mut int_state_repr,
-        &ctr_manifest,
-        "host_config",
-        "host_config",
-    ));
+    for (init_dir_key, internal_key) in OPTIONAL_INIT_DIR_KEYS {
+        try_or_print(update_template(
+            &mut int_state_repr,
+            &ctr_manifest,
+            internal_key,
+            init_dir_key,
+        ));
+    }
     Ok(serde_json::to_value(int_state_repr)?)
 }
 
 /// Use the JSON merge patch (RFC IETF 7386) to merge the manifest read from disk with a
 /// template containing all available options.
-fn expand_container_manifest(manifest: &Value) -> Result<Value, Box<dyn std::error::Error>> {
-    let ctr_config_template = include_str!("kanto_internal_ctr_repr.json.template.in");
-    let mut ctr_config_template = serde_json::from_str(ctr_config_template)?;
+pub(crate) fn expand_container_manifest(
+    manifest: &Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut ctr_config_template = internal_state_template()?;
     merge(&mut ctr_config_template, manifest);
     Ok(ctr_config_template)
 }
 
-pub fn try_parse_manifest(container_str: &str) -> Result<Container, Box<dyn std::error::Error>> {
-    let parsed_json: Container = match serde_json::from_str(container_str) {
+/// Removes the auto-deployer options from the manifest (if present) so that
+/// only the container definition is left for the parsing below.
+pub(crate) fn take_deployment_options(
+    manifest: &mut Value,
+) -> Result<DeploymentOptions, Box<dyn std::error::Error>> {
+    let options = manifest
+        .as_object_mut()
+        .and_then(|m| m.remove(DEPLOYMENT_OPTIONS_KEY));
+    match options {
+        Some(options) => Ok(serde_json::from_value(options)?),
+        None => Ok(DeploymentOptions::default()),
+    }
+}
+
+/// Parses a single container in the internal state representation or the init-dir format
+fn parse_container(manifest: &Value) -> Result<Container, Box<dyn std::error::Error>> {
+    let parsed_json: Container = match Container::deserialize(manifest) {
         Ok(ctr) => {
             log::debug!("Manifest is in auto-deployer format already. Deploying directly");
             ctr
         }
+        // A manifest that looks like the internal representation can not be converted either,
+        // report the actual problem instead of a missing init-dir key
+        Err(e) if detect_format(manifest) == Some(ManifestFormat::Internal) => {
+            return Err(Box::new(e));
+        }
         Err(_) => {
             log::debug!("Failed to load manifest directly. Will attempt auto-conversion from init-dir format.");
-            let manifest = serde_json::from_str(container_str)?;
-            let manifest = expand_container_manifest(&manifest)?;
+            let manifest = expand_container_manifest(manifest)?;
             let internal_state = map_to_internal_state_manifest(manifest)?;
 
             // pretty-printing is expensive
@@ -141,12 +483,48 @@ pub fn try_parse_manifest(container_str: &str) -> Result<Container, Box<dyn std:
             serde_json::from_value(internal_state)?
         }
     };
+    Ok(parsed_json)
+}
 
+fn log_deployed(container: &Container) -> Result<(), Box<dyn std::error::Error>> {
     if log::log_enabled!(log::Level::Debug) {
-        log::debug!(
-            "Deploying: \n {}",
-            serde_json::to_string_pretty(&parsed_json)?
-        );
+        log::debug!("Deploying: \n {}", serde_json::to_string_pretty(container)?);
     }
-    Ok(parsed_json)
+    Ok(())
+}
+
+/// Parses a manifest file into the containers it describes. This is a single one for all formats
+/// but Kubernetes pods and compose files, whose containers all share the auto-deployer options of the manifest.
+pub fn try_parse_manifest(
+    container_str: &str,
+    syntax: ManifestSyntax,
+    vars: &Variables,
+) -> Result<Vec<Manifest>, Box<dyn std::error::Error>> {
+    let mut manifest = parse_document(container_str, syntax)?;
+    substitute_variables(&mut manifest, vars)?;
+    let options = take_deployment_options(&mut manifest)?;
+
+    let conversion = match detect_format(&manifest) {
+        Some(ManifestFormat::Kubernetes) => kubernetes::convert(&manifest)?,
+        Some(ManifestFormat::Compose) => compose::convert(&manifest)?,
+        _ => {
+            let container = parse_container(&manifest)?;
+            log_deployed(&container)?;
+            return Ok(vec![Manifest { container, options }]);
+        }
+    };
+    for warning in &conversion.warnings {
+        log::warn!("[{}] {}", conversion.name, warning);
+    }
+    conversion
+        .containers
+        .into_iter()
+        .map(|converted| {
+            let container: Container = serde_json::from_value(converted.container)?;
+            log_deployed(&container)?;
+            let mut options = options.clone();
+            options.depends_on.extend(converted.depends_on);
+            Ok(Manifest { container, options })
+        })
+        .collect()
 }
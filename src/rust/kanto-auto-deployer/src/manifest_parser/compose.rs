@@ -0,0 +1,540 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Front-end for docker-compose files.
+//!
+//! Every service is mapped onto the internal state representation template, named after its
+//! `container_name` or else the service name. The mapped fields are image, hostname, command,
+//! environment, bind-mount volumes, published ports, privileged, network_mode, restart and depends_on.
+//! Everything else (builds, named volumes, networks, healthchecks, ...) is reported as a warning.
+//! Top-level `x-` extension fields are ignored, as in compose itself.
+use super::conversion::{
+    as_object, child, get_array, get_str, Conversion, ConvertedContainer, Warnings,
+};
+use super::{display_key_path, internal_state_template};
+use anyhow::anyhow;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+static TOP_LEVEL_FIELDS: [&str; 3] = ["services", "version", "name"];
+static SERVICE_FIELDS: [&str; 11] = [
+    "image",
+    "container_name",
+    "hostname",
+    "command",
+    "environment",
+    "volumes",
+    "ports",
+    "privileged",
+    "network_mode",
+    "restart",
+    "depends_on",
+];
+static PROPAGATION_MODES: [&str; 6] = [
+    "rprivate", "private", "rshared", "shared", "rslave", "slave",
+];
+
+/// Compose files are recognized by their `services` mapping
+pub fn is_compose(manifest: &Value) -> bool {
+    matches!(manifest.get("services"), Some(Value::Object(_)))
+}
+
+/// Scalars are allowed wherever compose expects a string (e.g. `PORT: 8080`)
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Converter {
+    warnings: Warnings,
+}
+
+impl Converter {
+    fn command(
+        &mut self,
+        service: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let cmd = match service.get("command") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::String(command)) => {
+                if command.contains(['"', '\'']) {
+                    self.warnings.warn(
+                        child(path, "command"),
+                        "quotes are not interpreted, use the list form for arguments with spaces",
+                    );
+                }
+                command
+                    .split_whitespace()
+                    .map(|arg| Value::String(String::from(arg)))
+                    .collect()
+            }
+            Some(Value::Array(args)) => args
+                .iter()
+                .map(|arg| scalar_to_string(arg).map(Value::String))
+                .collect::<Option<Vec<Value>>>()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "`{}` must be a list of strings",
+                        display_key_path(&child(path, "command"))
+                    )
+                })?,
+            Some(_) => {
+                return Err(anyhow!(
+                    "`{}` must be a string or a list",
+                    display_key_path(&child(path, "command"))
+                )
+                .into())
+            }
+        };
+        Ok(cmd)
+    }
+
+    /// Variables without a value are taken from the environment of the auto-deployer, as compose does
+    fn environment(
+        &mut self,
+        service: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let env_path = child(path, "environment");
+        let variables: Vec<(String, Option<String>)> = match service.get("environment") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Object(vars)) => vars
+                .iter()
+                .map(|(name, value)| (name.clone(), scalar_to_string(value)))
+                .collect(),
+            Some(Value::Array(vars)) => vars
+                .iter()
+                .map(|var| {
+                    let var = var.as_str().ok_or_else(|| {
+                        anyhow!(
+                            "`{}` must be a list of strings",
+                            display_key_path(&env_path)
+                        )
+                    })?;
+                    Ok(match var.split_once('=') {
+                        Some((name, value)) => (String::from(name), Some(String::from(value))),
+                        None => (String::from(var), None),
+                    })
+                })
+                .collect::<Result<_, Box<dyn std::error::Error>>>()?,
+            Some(_) => {
+                return Err(anyhow!(
+                    "`{}` must be a mapping or a list",
+                    display_key_path(&env_path)
+                )
+                .into())
+            }
+        };
+
+        Ok(variables
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let value = value.or_else(|| std::env::var(&name).ok())?;
+                Some(Value::String(format!("{}={}", name, value)))
+            })
+            .collect())
+    }
+
+    fn bind_mount(
+        &mut self,
+        source: &str,
+        target: &str,
+        propagation: Option<&str>,
+        path: Vec<String>,
+    ) -> Option<Value> {
+        if !source.starts_with('/') {
+            let message = if source.starts_with('.') || source.starts_with('~') {
+                "relative host paths are not supported, use an absolute path (or a ${VAR})"
+            } else {
+                "named volumes are not supported, only bind mounts of absolute host paths"
+            };
+            self.warnings.warn(path, message);
+            return None;
+        }
+        Some(json!({
+            "destination": target,
+            "source": source,
+            "propagation_mode": propagation.unwrap_or("rprivate"),
+        }))
+    }
+
+    fn volumes(
+        &mut self,
+        service: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut mounts = Vec::new();
+        let list_path = child(path, "volumes");
+        for (i, volume) in get_array(service, "volumes", path)?.iter().enumerate() {
+            let volume_path = child(&list_path, &format!("[{}]", i));
+            let mount = match volume {
+                // SOURCE:TARGET[:OPTIONS]
+                Value::String(spec) => {
+                    let parts: Vec<&str> = spec.split(':').collect();
+                    let (source, target, options) = match parts[..] {
+                        [source, target] => (source, target, ""),
+                        [source, target, options] => (source, target, options),
+                        _ => {
+                            self.warnings.warn(
+                                volume_path,
+                                "anonymous volumes are not supported, only bind mounts of absolute host paths",
+                            );
+                            continue;
+                        }
+                    };
+                    let mut propagation = None;
+                    for option in options.split(',').filter(|o| !o.is_empty()) {
+                        match option {
+                            "rw" => {}
+                            _ if PROPAGATION_MODES.contains(&option) => propagation = Some(option),
+                            _ => self.warnings.warn(
+                                volume_path.clone(),
+                                format!("volume option \"{}\" is not supported, ignored", option),
+                            ),
+                        }
+                    }
+                    self.bind_mount(source, target, propagation, volume_path)
+                }
+                Value::Object(long) => {
+                    self.warnings.unsupported(
+                        long,
+                        &["type", "source", "target", "bind"],
+                        &volume_path,
+                    );
+                    if get_str(long, "type", &volume_path)? != Some("bind") {
+                        self.warnings
+                            .warn(volume_path, "only volumes of type bind are supported");
+                        continue;
+                    }
+                    let source = get_str(long, "source", &volume_path)?.ok_or_else(|| {
+                        anyhow!("`{}` has no source", display_key_path(&volume_path))
+                    })?;
+                    let target = get_str(long, "target", &volume_path)?.ok_or_else(|| {
+                        anyhow!("`{}` has no target", display_key_path(&volume_path))
+                    })?;
+                    let propagation = match long.get("bind") {
+                        Some(bind) => {
+                            let bind_path = child(&volume_path, "bind");
+                            let bind = as_object(bind, &bind_path)?;
+                            self.warnings
+                                .unsupported(bind, &["propagation"], &bind_path);
+                            get_str(bind, "propagation", &bind_path)?
+                        }
+                        None => None,
+                    };
+                    self.bind_mount(source, target, propagation, volume_path)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "`{}` must be a string or a mapping",
+                        display_key_path(&volume_path)
+                    )
+                    .into())
+                }
+            };
+            mounts.extend(mount);
+        }
+        Ok(mounts)
+    }
+
+    /// Parses `[HOST_IP:]HOST_PORT[-HOST_PORT_END]:CONTAINER_PORT[/PROTOCOL]`
+    fn short_port(
+        &mut self,
+        spec: &str,
+        path: Vec<String>,
+    ) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+        let invalid = || anyhow!("`{}`: invalid port \"{}\"", display_key_path(&path), spec);
+        let (ports, proto) = spec.rsplit_once('/').unwrap_or((spec, "tcp"));
+        let parts: Vec<&str> = ports.split(':').collect();
+        let (host_ip, host_ports, container_port) = match parts[..] {
+            [container_port] => ("0.0.0.0", "", container_port),
+            [host_ports, container_port] => ("0.0.0.0", host_ports, container_port),
+            [host_ip, host_ports, container_port] => (host_ip, host_ports, container_port),
+            _ => return Err(invalid().into()),
+        };
+        if host_ports.is_empty() {
+            self.warnings
+                .warn(path, "no host port, the port is not published on the host");
+            return Ok(None);
+        }
+        let container_port: i64 = match container_port.parse() {
+            Ok(p) => p,
+            Err(_) if container_port.contains('-') => {
+                self.warnings
+                    .warn(path, "container port ranges are not supported, ignored");
+                return Ok(None);
+            }
+            Err(_) => return Err(invalid().into()),
+        };
+        let (host_port, host_port_end) = match host_ports.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (host_ports, host_ports),
+        };
+        Ok(Some(json!({
+            "proto": proto,
+            "container_port": container_port,
+            "host_ip": if host_ip.is_empty() { "0.0.0.0" } else { host_ip },
+            "host_port": host_port.parse::<i64>().map_err(|_| invalid())?,
+            "host_port_end": host_port_end.parse::<i64>().map_err(|_| invalid())?,
+        })))
+    }
+
+    fn ports(
+        &mut self,
+        service: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut port_mappings = Vec::new();
+        let list_path = child(path, "ports");
+        for (i, port) in get_array(service, "ports", path)?.iter().enumerate() {
+            let port_path = child(&list_path, &format!("[{}]", i));
+            let mapping = match port {
+                Value::String(spec) => self.short_port(spec, port_path)?,
+                Value::Number(n) => self.short_port(&n.to_string(), port_path)?,
+                Value::Object(long) => {
+                    self.warnings.unsupported(
+                        long,
+                        &["target", "published", "host_ip", "protocol", "mode"],
+                        &port_path,
+                    );
+                    let target = long.get("target").and_then(Value::as_i64).ok_or_else(|| {
+                        anyhow!("`{}` needs a numeric target", display_key_path(&port_path))
+                    })?;
+                    let published = match long.get("published").and_then(scalar_to_string) {
+                        Some(p) => p,
+                        None => {
+                            self.warnings.warn(port_path, "not published on the host");
+                            continue;
+                        }
+                    };
+                    let spec = format!(
+                        "{}:{}:{}/{}",
+                        get_str(long, "host_ip", &port_path)?.unwrap_or("0.0.0.0"),
+                        published,
+                        target,
+                        get_str(long, "protocol", &port_path)?.unwrap_or("tcp")
+                    );
+                    self.short_port(&spec, port_path)?
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "`{}` must be a string, a number or a mapping",
+                        display_key_path(&port_path)
+                    )
+                    .into())
+                }
+            };
+            port_mappings.extend(mapping);
+        }
+        Ok(port_mappings)
+    }
+
+    /// Restart policy type and maximum retry count (`on-failure[:max-retries]`)
+    fn restart(
+        &mut self,
+        service: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Option<(String, i64)>, Box<dyn std::error::Error>> {
+        let restart = match get_str(service, "restart", path)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let policy = match restart.split_once(':') {
+            Some(("on-failure", retries)) => {
+                let retries = retries.parse().map_err(|_| {
+                    anyhow!(
+                        "`{}`: invalid retry count \"{}\"",
+                        display_key_path(&child(path, "restart")),
+                        retries
+                    )
+                })?;
+                Some((String::from("on-failure"), retries))
+            }
+            None if ["no", "always", "on-failure", "unless-stopped"].contains(&restart) => {
+                Some((String::from(restart), 0))
+            }
+            _ => {
+                self.warnings.warn(
+                    child(path, "restart"),
+                    format!(
+                        "unknown restart policy \"{}\", using the template default",
+                        restart
+                    ),
+                );
+                None
+            }
+        };
+        Ok(policy)
+    }
+
+    /// Maps the service names to the names of their containers
+    fn depends_on(
+        &mut self,
+        service: &Map<String, Value>,
+        container_names: &HashMap<&str, String>,
+        path: &[String],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let deps_path = child(path, "depends_on");
+        let services: Vec<&str> = match service.get("depends_on") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(services)) => services
+                .iter()
+                .map(Value::as_str)
+                .collect::<Option<_>>()
+                .ok_or_else(|| {
+                    anyhow!(
+                        "`{}` must be a list of strings",
+                        display_key_path(&deps_path)
+                    )
+                })?,
+            // Long syntax, conditions can not be checked as there are no healthchecks
+            Some(Value::Object(services)) => services.keys().map(String::as_str).collect(),
+            Some(_) => {
+                return Err(anyhow!(
+                    "`{}` must be a list or a mapping",
+                    display_key_path(&deps_path)
+                )
+                .into())
+            }
+        };
+        services
+            .into_iter()
+            .map(|s| {
+                container_names.get(s).cloned().ok_or_else(|| {
+                    anyhow!(
+                        "`{}`: unknown service [{}]",
+                        display_key_path(&deps_path),
+                        s
+                    )
+                    .into()
+                })
+            })
+            .collect()
+    }
+
+    fn service(
+        &mut self,
+        service: &Value,
+        container_name: &str,
+        container_names: &HashMap<&str, String>,
+        path: &[String],
+    ) -> Result<ConvertedContainer, Box<dyn std::error::Error>> {
+        let service = as_object(service, path)?;
+        self.warnings.unsupported(service, &SERVICE_FIELDS, path);
+
+        let image = get_str(service, "image", path)?.ok_or_else(|| {
+            anyhow!(
+                "`{}` has no image, building images is not supported",
+                display_key_path(path)
+            )
+        })?;
+
+        let mut ctr = internal_state_template()?;
+        ctr["name"] = Value::String(String::from(container_name));
+        ctr["image"]["name"] = Value::String(String::from(image));
+        if let Some(host_name) = get_str(service, "hostname", path)? {
+            ctr["host_name"] = Value::String(String::from(host_name));
+        }
+        ctr["config"]["cmd"] = Value::Array(self.command(service, path)?);
+        ctr["config"]["env"] = Value::Array(self.environment(service, path)?);
+        ctr["mounts"] = Value::Array(self.volumes(service, path)?);
+        ctr["host_config"]["port_mappings"] = Value::Array(self.ports(service, path)?);
+        if let Some(privileged) = service.get("privileged").and_then(Value::as_bool) {
+            ctr["host_config"]["privileged"] = Value::Bool(privileged);
+        }
+        match get_str(service, "network_mode", path)? {
+            Some(mode @ ("host" | "bridge")) => {
+                ctr["host_config"]["network_mode"] = Value::String(String::from(mode))
+            }
+            Some(mode) => self.warnings.warn(
+                child(path, "network_mode"),
+                format!(
+                    "network mode \"{}\" is not supported, using the template default",
+                    mode
+                ),
+            ),
+            None => {}
+        }
+        if let Some((policy, retries)) = self.restart(service, path)? {
+            ctr["host_config"]["restart_policy"]["type"] = Value::String(policy);
+            ctr["host_config"]["restart_policy"]["maximum_retry_count"] = json!(retries);
+        }
+
+        Ok(ConvertedContainer {
+            container: ctr,
+            depends_on: self.depends_on(service, container_names, path)?,
+        })
+    }
+}
+
+/// Converts every service of a compose file into the internal state representation.
+/// Dependencies between the services are kept, so they are deployed in the same order as compose would.
+pub fn convert(manifest: &Value) -> Result<Conversion, Box<dyn std::error::Error>> {
+    let mut converter = Converter::default();
+    let manifest = as_object(manifest, &[])?;
+    let top_level: Map<String, Value> = manifest
+        .iter()
+        .filter(|(k, _)| !k.starts_with("x-"))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    converter
+        .warnings
+        .unsupported(&top_level, &TOP_LEVEL_FIELDS, &[]);
+
+    let services_path = vec![String::from("services")];
+    let services = manifest
+        .get("services")
+        .ok_or_else(|| anyhow!("`services` is required"))?;
+    let services = as_object(services, &services_path)?;
+    if services.is_empty() {
+        return Err(anyhow!("`services` is empty").into());
+    }
+
+    let mut container_names = HashMap::new();
+    for (service_name, service) in services {
+        let service_path = child(&services_path, service_name);
+        let container_name = match service.get("container_name") {
+            Some(name) => name.as_str().ok_or_else(|| {
+                anyhow!(
+                    "`{}.container_name` must be a string",
+                    display_key_path(&service_path)
+                )
+            })?,
+            None => service_name,
+        };
+        container_names.insert(service_name.as_str(), String::from(container_name));
+    }
+
+    let mut containers = Vec::with_capacity(services.len());
+    for (service_name, service) in services {
+        let service_path = child(&services_path, service_name);
+        containers.push(converter.service(
+            service,
+            &container_names[service_name.as_str()],
+            &container_names,
+            &service_path,
+        )?);
+    }
+
+    Ok(Conversion {
+        name: String::from(get_str(manifest, "name", &[])?.unwrap_or("compose")),
+        containers,
+        warnings: converter.warnings.into_inner(),
+    })
+}
@@ -0,0 +1,121 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Building blocks shared by the front-ends for foreign manifest formats (Kubernetes, compose,
+//! VUM desired state).
+//!
+//! These work on the untyped document, so that every field can be accounted for: either it is
+//! mapped onto the internal state representation, or a warning is recorded for it.
+use super::display_key_path;
+use anyhow::anyhow;
+use serde_json::{Map, Value};
+use std::fmt::Display;
+
+/// A field of the manifest that could not be mapped (fully) to Kanto-CM
+#[derive(Debug, Clone)]
+pub struct ConversionWarning {
+    /// Key path of the field, e.g. `["spec", "containers", "[0]", "livenessProbe"]`
+    pub field: Vec<String>,
+    pub message: String,
+}
+
+impl Display for ConversionWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}`: {}", display_key_path(&self.field), self.message)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConvertedContainer {
+    /// Internal state representation of the container
+    pub container: Value,
+    /// Names of the containers defined in the same manifest that have to be deployed first
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Conversion {
+    /// Name of the pod, deployment, compose project or desired state domain
+    pub name: String,
+    pub containers: Vec<ConvertedContainer>,
+    pub warnings: Vec<ConversionWarning>,
+}
+
+#[derive(Debug, Default)]
+pub struct Warnings(Vec<ConversionWarning>);
+
+impl Warnings {
+    pub fn warn(&mut self, field: Vec<String>, message: impl Into<String>) {
+        self.0.push(ConversionWarning {
+            field,
+            message: message.into(),
+        });
+    }
+
+    /// Warns about every key of the object that is not in the supported list
+    pub fn unsupported(
+        &mut self,
+        object: &Map<String, Value>,
+        supported: &[&str],
+        path: &[String],
+    ) {
+        for key in object.keys().filter(|k| !supported.contains(&k.as_str())) {
+            self.warn(child(path, key), "not supported by Kanto-CM, ignored");
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<ConversionWarning> {
+        self.0
+    }
+}
+
+pub fn child(path: &[String], key: &str) -> Vec<String> {
+    let mut path = path.to_vec();
+    path.push(String::from(key));
+    path
+}
+
+pub fn as_object<'a>(
+    value: &'a Value,
+    path: &[String],
+) -> Result<&'a Map<String, Value>, Box<dyn std::error::Error>> {
+    value
+        .as_object()
+        .ok_or_else(|| anyhow!("`{}` must be a mapping", display_key_path(path)).into())
+}
+
+pub fn get_str<'a>(
+    object: &'a Map<String, Value>,
+    key: &str,
+    path: &[String],
+) -> Result<Option<&'a str>, Box<dyn std::error::Error>> {
+    match object.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => {
+            Err(anyhow!("`{}` must be a string", display_key_path(&child(path, key))).into())
+        }
+    }
+}
+
+pub fn get_array<'a>(
+    object: &'a Map<String, Value>,
+    key: &str,
+    path: &[String],
+) -> Result<&'a [Value], Box<dyn std::error::Error>> {
+    match object.get(key) {
+        None | Some(Value::Null) => Ok(&[]),
+        Some(Value::Array(items)) => Ok(items),
+        Some(_) => Err(anyhow!("`{}` must be a list", display_key_path(&child(path, key))).into()),
+    }
+}
@@ -0,0 +1,365 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Front-end for the "containers" domain of VUM desired state messages.
+//!
+//! Every component becomes a container named after its id. The component config is a list of
+//! key/value pairs with the same keys as the Kanto container update agent uses: image, env, cmd,
+//! mount, device, port, host, network, privileged, terminal, interactive, the restart policy
+//! (restartPolicy, restartMaxRetries, restartTimeout) and the log settings (logDriver, logMaxFiles,
+//! logMaxSize, logMode). Unknown keys are reported as warnings.
+use super::conversion::{
+    as_object, child, get_array, get_str, Conversion, ConvertedContainer, Warnings,
+};
+use super::{display_key_path, internal_state_template};
+use anyhow::anyhow;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+pub static CONTAINERS_DOMAIN: &str = "containers";
+
+static COMPONENT_FIELDS: [&str; 3] = ["id", "version", "config"];
+static PROPAGATION_MODES: [&str; 6] = [
+    "rprivate", "private", "rshared", "shared", "rslave", "slave",
+];
+
+/// The containers domain of a desired state, converted
+#[derive(Debug)]
+pub struct DesiredContainers {
+    pub conversion: Conversion,
+    /// Version of every component, by container name
+    pub versions: HashMap<String, String>,
+}
+
+/// An entry of a `config` list
+struct KeyValue<'a> {
+    key: &'a str,
+    value: &'a str,
+    path: Vec<String>,
+}
+
+/// Reads the `config` list of key/value pairs, keeping the order of repeated keys
+fn key_values<'a>(
+    component: &'a Map<String, Value>,
+    path: &[String],
+) -> Result<Vec<KeyValue<'a>>, Box<dyn std::error::Error>> {
+    let list_path = child(path, "config");
+    get_array(component, "config", path)?
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let entry_path = child(&list_path, &format!("[{}]", i));
+            let entry = as_object(entry, &entry_path)?;
+            let key = get_str(entry, "key", &entry_path)?
+                .ok_or_else(|| anyhow!("`{}` has no key", display_key_path(&entry_path)))?;
+            let value = get_str(entry, "value", &entry_path)?.unwrap_or_default();
+            Ok(KeyValue {
+                key,
+                value,
+                path: entry_path,
+            })
+        })
+        .collect()
+}
+
+fn parse_bool(value: &str, path: &[String]) -> Result<bool, Box<dyn std::error::Error>> {
+    value.parse().map_err(|_| {
+        anyhow!(
+            "`{}`: expected \"true\" or \"false\", got \"{}\"",
+            display_key_path(path),
+            value
+        )
+        .into()
+    })
+}
+
+fn parse_int(value: &str, path: &[String]) -> Result<i64, Box<dyn std::error::Error>> {
+    value.parse().map_err(|_| {
+        anyhow!(
+            "`{}`: expected a number, got \"{}\"",
+            display_key_path(path),
+            value
+        )
+        .into()
+    })
+}
+
+#[derive(Default)]
+struct Converter {
+    warnings: Warnings,
+}
+
+impl Converter {
+    /// Parses `SOURCE:DESTINATION[:PROPAGATION]`
+    fn mount(
+        &mut self,
+        spec: &str,
+        path: Vec<String>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (source, destination, propagation) = match parts[..] {
+            [source, destination] => (source, destination, "rprivate"),
+            [source, destination, propagation] => (source, destination, propagation),
+            _ => {
+                return Err(
+                    anyhow!("`{}`: invalid mount \"{}\"", display_key_path(&path), spec).into(),
+                )
+            }
+        };
+        let propagation = if PROPAGATION_MODES.contains(&propagation) {
+            propagation
+        } else {
+            self.warnings.warn(
+                path,
+                format!(
+                    "unknown propagation mode \"{}\", using rprivate",
+                    propagation
+                ),
+            );
+            "rprivate"
+        };
+        Ok(json!({
+            "destination": destination,
+            "source": source,
+            "propagation_mode": propagation,
+        }))
+    }
+
+    /// Parses `PATH_ON_HOST:PATH_IN_CONTAINER[:CGROUP_PERMISSIONS]`
+    fn device(&mut self, spec: &str, path: &[String]) -> Result<Value, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (on_host, in_container, permissions) = match parts[..] {
+            [on_host, in_container] => (on_host, in_container, "rwm"),
+            [on_host, in_container, permissions] => (on_host, in_container, permissions),
+            _ => {
+                return Err(
+                    anyhow!("`{}`: invalid device \"{}\"", display_key_path(path), spec).into(),
+                )
+            }
+        };
+        Ok(json!({
+            "path_on_host": on_host,
+            "path_in_container": in_container,
+            "cgroup_permissions": permissions,
+        }))
+    }
+
+    /// Parses `[HOST_IP:]CONTAINER_PORT:HOST_PORT[-HOST_PORT_END][/PROTOCOL]`.
+    /// Note the container port comes first, unlike in compose files.
+    fn port(&mut self, spec: &str, path: &[String]) -> Result<Value, Box<dyn std::error::Error>> {
+        let invalid = || anyhow!("`{}`: invalid port \"{}\"", display_key_path(path), spec);
+        let (ports, proto) = spec.rsplit_once('/').unwrap_or((spec, "tcp"));
+        let parts: Vec<&str> = ports.split(':').collect();
+        let (host_ip, container_port, host_ports) = match parts[..] {
+            [container_port, host_ports] => ("0.0.0.0", container_port, host_ports),
+            [host_ip, container_port, host_ports] => (host_ip, container_port, host_ports),
+            _ => return Err(invalid().into()),
+        };
+        let (host_port, host_port_end) = host_ports
+            .split_once('-')
+            .unwrap_or((host_ports, host_ports));
+        Ok(json!({
+            "proto": proto,
+            "container_port": container_port.parse::<i64>().map_err(|_| invalid())?,
+            "host_ip": if host_ip == "localhost" { "127.0.0.1" } else { host_ip },
+            "host_port": host_port.parse::<i64>().map_err(|_| invalid())?,
+            "host_port_end": host_port_end.parse::<i64>().map_err(|_| invalid())?,
+        }))
+    }
+
+    /// Checks a value against the ones supported by Kanto-CM, warning about others
+    fn one_of(&mut self, value: &str, supported: &[&str], path: Vec<String>) -> Option<Value> {
+        if supported.contains(&value) {
+            Some(Value::String(String::from(value)))
+        } else {
+            self.warnings.warn(
+                path,
+                format!(
+                    "\"{}\" is not supported (expected one of {}), using the template default",
+                    value,
+                    supported.join(", ")
+                ),
+            );
+            None
+        }
+    }
+
+    fn component(
+        &mut self,
+        component: &Value,
+        path: &[String],
+    ) -> Result<(ConvertedContainer, String), Box<dyn std::error::Error>> {
+        let component = as_object(component, path)?;
+        self.warnings
+            .unsupported(component, &COMPONENT_FIELDS, path);
+        let id = get_str(component, "id", path)?
+            .ok_or_else(|| anyhow!("`{}` has no id", display_key_path(path)))?;
+        let version = get_str(component, "version", path)?.unwrap_or_default();
+
+        let mut ctr = internal_state_template()?;
+        ctr["name"] = Value::String(String::from(id));
+        let mut image = None;
+        for KeyValue {
+            key,
+            value,
+            path: entry_path,
+        } in key_values(component, path)?
+        {
+            match key {
+                "image" => image = Some(value),
+                "env" => push(
+                    &mut ctr["config"]["env"],
+                    Value::String(String::from(value)),
+                ),
+                "cmd" => push(
+                    &mut ctr["config"]["cmd"],
+                    Value::String(String::from(value)),
+                ),
+                "mount" => {
+                    let mount = self.mount(value, entry_path)?;
+                    push(&mut ctr["mounts"], mount)
+                }
+                "device" => {
+                    let device = self.device(value, &entry_path)?;
+                    push(&mut ctr["host_config"]["devices"], device)
+                }
+                "port" => {
+                    let port = self.port(value, &entry_path)?;
+                    push(&mut ctr["host_config"]["port_mappings"], port)
+                }
+                "host" => push(
+                    &mut ctr["host_config"]["extra_hosts"],
+                    Value::String(String::from(value)),
+                ),
+                "network" => {
+                    if let Some(mode) = self.one_of(value, &["bridge", "host"], entry_path) {
+                        ctr["host_config"]["network_mode"] = mode;
+                    }
+                }
+                "privileged" => {
+                    ctr["host_config"]["privileged"] = json!(parse_bool(value, &entry_path)?)
+                }
+                "terminal" => ctr["io_config"]["tty"] = json!(parse_bool(value, &entry_path)?),
+                "interactive" => {
+                    ctr["io_config"]["open_stdin"] = json!(parse_bool(value, &entry_path)?)
+                }
+                "restartPolicy" => {
+                    let policies = ["no", "always", "on-failure", "unless-stopped"];
+                    if let Some(policy) = self.one_of(value, &policies, entry_path) {
+                        ctr["host_config"]["restart_policy"]["type"] = policy;
+                    }
+                }
+                "restartMaxRetries" => {
+                    ctr["host_config"]["restart_policy"]["maximum_retry_count"] =
+                        json!(parse_int(value, &entry_path)?)
+                }
+                "restartTimeout" => {
+                    ctr["host_config"]["restart_policy"]["retry_timeout"] =
+                        json!(parse_int(value, &entry_path)?)
+                }
+                "logDriver" => {
+                    if let Some(driver) = self.one_of(value, &["json-file", "none"], entry_path) {
+                        ctr["host_config"]["log_config"]["driver_config"]["type"] = driver;
+                    }
+                }
+                "logMaxFiles" => {
+                    ctr["host_config"]["log_config"]["driver_config"]["max_files"] =
+                        json!(parse_int(value, &entry_path)?)
+                }
+                "logMaxSize" => {
+                    ctr["host_config"]["log_config"]["driver_config"]["max_size"] =
+                        Value::String(String::from(value))
+                }
+                "logMode" => {
+                    if let Some(mode) =
+                        self.one_of(value, &["blocking", "non-blocking"], entry_path)
+                    {
+                        ctr["host_config"]["log_config"]["mode_config"]["mode"] = mode;
+                    }
+                }
+                _ => self
+                    .warnings
+                    .warn(entry_path, format!("unknown key \"{}\", ignored", key)),
+            }
+        }
+        let image = image.ok_or_else(|| {
+            anyhow!(
+                "`{}` [{}] has no image in its config",
+                display_key_path(path),
+                id
+            )
+        })?;
+        ctr["image"]["name"] = Value::String(String::from(image));
+
+        let converted = ConvertedContainer {
+            container: ctr,
+            depends_on: Vec::new(),
+        };
+        Ok((converted, String::from(version)))
+    }
+}
+
+fn push(list: &mut Value, item: Value) {
+    if let Value::Array(items) = list {
+        items.push(item);
+    }
+}
+
+/// Converts the components of the containers domain of a desired state payload
+/// (the `payload` of the VUM envelope) into the internal state representation.
+pub fn convert(desired_state: &Value) -> Result<DesiredContainers, Box<dyn std::error::Error>> {
+    let mut converter = Converter::default();
+    let desired_state = as_object(desired_state, &[])?;
+    let domains_path = vec![String::from("domains")];
+    let (domain_path, domain) = get_array(desired_state, "domains", &[])?
+        .iter()
+        .enumerate()
+        .find(|(_, d)| d.get("id").and_then(Value::as_str) == Some(CONTAINERS_DOMAIN))
+        .map(|(i, d)| (child(&domains_path, &format!("[{}]", i)), d))
+        .ok_or_else(|| anyhow!("No \"{}\" domain in the desired state", CONTAINERS_DOMAIN))?;
+    let domain = as_object(domain, &domain_path)?;
+    for entry in key_values(domain, &domain_path)? {
+        converter.warnings.warn(
+            entry.path,
+            format!("domain option \"{}\" is not supported, ignored", entry.key),
+        );
+    }
+
+    let components_path = child(&domain_path, "components");
+    let mut containers = Vec::new();
+    let mut versions = HashMap::new();
+    for (i, component) in get_array(domain, "components", &domain_path)?
+        .iter()
+        .enumerate()
+    {
+        let component_path = child(&components_path, &format!("[{}]", i));
+        let (converted, version) = converter.component(component, &component_path)?;
+        let name = converted.container["name"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_default();
+        if versions.insert(name.clone(), version).is_some() {
+            return Err(anyhow!("Component [{}] is defined more than once", name).into());
+        }
+        containers.push(converted);
+    }
+
+    Ok(DesiredContainers {
+        conversion: Conversion {
+            name: String::from(CONTAINERS_DOMAIN),
+            containers,
+            warnings: converter.warnings.into_inner(),
+        },
+        versions,
+    })
+}
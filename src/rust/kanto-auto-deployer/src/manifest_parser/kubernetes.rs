@@ -0,0 +1,416 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Front-end for Kubernetes `Pod` and `Deployment` manifests.
+//!
+//! Every container of the pod is mapped onto the internal state representation template.
+//! Only the fields with an equivalent in Kanto-CM are mapped: image, command/args, env,
+//! hostPath volume mounts, hostNetwork, the privileged security context, host ports and the restart policy.
+//! Everything else is reported as a warning, as the workload may behave differently than in a cluster.
+//! That includes metadata other than the name (labels, annotations, ...), which has no meaning for Kanto-CM.
+use super::conversion::{
+    as_object, child, get_array, get_str, Conversion, ConvertedContainer, Warnings,
+};
+use super::{display_key_path, internal_state_template};
+use anyhow::anyhow;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+static TOP_LEVEL_FIELDS: [&str; 4] = ["apiVersion", "kind", "metadata", "spec"];
+static METADATA_FIELDS: [&str; 1] = ["name"];
+static POD_SPEC_FIELDS: [&str; 4] = ["containers", "volumes", "hostNetwork", "restartPolicy"];
+static CONTAINER_FIELDS: [&str; 8] = [
+    "name",
+    "image",
+    "command",
+    "args",
+    "env",
+    "volumeMounts",
+    "securityContext",
+    "ports",
+];
+
+/// Kubernetes objects are recognized by their mandatory `apiVersion` and `kind` keys
+pub fn is_kubernetes(manifest: &Value) -> bool {
+    manifest.get("apiVersion").is_some() && manifest.get("kind").is_some()
+}
+
+#[derive(Default)]
+struct Converter {
+    warnings: Warnings,
+}
+
+impl Converter {
+    /// Names of the volumes and the host paths they refer to (None for other volume types)
+    fn volumes(
+        &mut self,
+        pod_spec: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<HashMap<String, Option<String>>, Box<dyn std::error::Error>> {
+        let mut volumes = HashMap::new();
+        let list_path = child(path, "volumes");
+        for (i, volume) in get_array(pod_spec, "volumes", path)?.iter().enumerate() {
+            let volume_path = child(&list_path, &format!("[{}]", i));
+            let volume = as_object(volume, &volume_path)?;
+            let name = get_str(volume, "name", &volume_path)?
+                .ok_or_else(|| anyhow!("`{}` has no name", display_key_path(&volume_path)))?;
+            let host_path = match volume.get("hostPath") {
+                Some(host_path) => {
+                    let host_path_path = child(&volume_path, "hostPath");
+                    let host_path = as_object(host_path, &host_path_path)?;
+                    self.warnings
+                        .unsupported(host_path, &["path"], &host_path_path);
+                    let source = get_str(host_path, "path", &host_path_path)?.ok_or_else(|| {
+                        anyhow!("`{}` has no path", display_key_path(&host_path_path))
+                    })?;
+                    Some(String::from(source))
+                }
+                None => {
+                    self.warnings.warn(
+                        volume_path,
+                        format!(
+                            "only hostPath volumes are supported, [{}] is not mounted",
+                            name
+                        ),
+                    );
+                    None
+                }
+            };
+            volumes.insert(String::from(name), host_path);
+        }
+        Ok(volumes)
+    }
+
+    fn restart_policy(
+        &mut self,
+        pod_spec: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Option<&'static str>, Box<dyn std::error::Error>> {
+        let policy = match get_str(pod_spec, "restartPolicy", path)? {
+            // Kubernetes restarts containers unless told otherwise
+            None | Some("Always") => Some("always"),
+            Some("OnFailure") => Some("on-failure"),
+            Some("Never") => Some("no"),
+            Some(other) => {
+                self.warnings.warn(
+                    child(path, "restartPolicy"),
+                    format!(
+                        "unknown restart policy \"{}\", using the template default",
+                        other
+                    ),
+                );
+                None
+            }
+        };
+        Ok(policy)
+    }
+
+    fn env(
+        &mut self,
+        container: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut env = Vec::new();
+        let list_path = child(path, "env");
+        for (i, var) in get_array(container, "env", path)?.iter().enumerate() {
+            let var_path = child(&list_path, &format!("[{}]", i));
+            let var = as_object(var, &var_path)?;
+            let name = get_str(var, "name", &var_path)?
+                .ok_or_else(|| anyhow!("`{}` has no name", display_key_path(&var_path)))?;
+            self.warnings
+                .unsupported(var, &["name", "value"], &var_path);
+            if var.contains_key("valueFrom") {
+                continue;
+            }
+            let value = get_str(var, "value", &var_path)?.unwrap_or_default();
+            env.push(Value::String(format!("{}={}", name, value)));
+        }
+        Ok(env)
+    }
+
+    fn mounts(
+        &mut self,
+        container: &Map<String, Value>,
+        volumes: &HashMap<String, Option<String>>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut mounts = Vec::new();
+        let list_path = child(path, "volumeMounts");
+        for (i, mount) in get_array(container, "volumeMounts", path)?
+            .iter()
+            .enumerate()
+        {
+            let mount_path = child(&list_path, &format!("[{}]", i));
+            let mount = as_object(mount, &mount_path)?;
+            self.warnings.unsupported(
+                mount,
+                &["name", "mountPath", "mountPropagation"],
+                &mount_path,
+            );
+            let name = get_str(mount, "name", &mount_path)?
+                .ok_or_else(|| anyhow!("`{}` has no name", display_key_path(&mount_path)))?;
+            let destination = get_str(mount, "mountPath", &mount_path)?
+                .ok_or_else(|| anyhow!("`{}` has no mountPath", display_key_path(&mount_path)))?;
+            let source = match volumes.get(name) {
+                Some(Some(source)) => source,
+                // Already reported together with the volume
+                Some(None) => continue,
+                None => {
+                    return Err(anyhow!(
+                        "`{}` refers to the undefined volume [{}]",
+                        display_key_path(&mount_path),
+                        name
+                    )
+                    .into())
+                }
+            };
+            let propagation_mode = match get_str(mount, "mountPropagation", &mount_path)? {
+                None | Some("None") => "rprivate",
+                Some("HostToContainer") => "rslave",
+                Some("Bidirectional") => "rshared",
+                Some(other) => {
+                    return Err(anyhow!(
+                        "`{}` has the unknown mountPropagation \"{}\"",
+                        display_key_path(&mount_path),
+                        other
+                    )
+                    .into())
+                }
+            };
+            mounts.push(json!({
+                "destination": destination,
+                "source": source,
+                "propagation_mode": propagation_mode,
+            }));
+        }
+        Ok(mounts)
+    }
+
+    fn port_mappings(
+        &mut self,
+        container: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut port_mappings = Vec::new();
+        let list_path = child(path, "ports");
+        for (i, port) in get_array(container, "ports", path)?.iter().enumerate() {
+            let port_path = child(&list_path, &format!("[{}]", i));
+            let port = as_object(port, &port_path)?;
+            self.warnings.unsupported(
+                port,
+                &["name", "containerPort", "hostPort", "hostIP", "protocol"],
+                &port_path,
+            );
+            let container_port = port
+                .get("containerPort")
+                .and_then(Value::as_i64)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "`{}` needs a numeric containerPort",
+                        display_key_path(&port_path)
+                    )
+                })?;
+            let host_port = match port.get("hostPort").and_then(Value::as_i64) {
+                Some(p) => p,
+                None => {
+                    self.warnings.warn(
+                        port_path,
+                        "no hostPort, the port is not published on the host",
+                    );
+                    continue;
+                }
+            };
+            let proto = match get_str(port, "protocol", &port_path)? {
+                None | Some("TCP") => "tcp",
+                Some("UDP") => "udp",
+                Some(other) => {
+                    return Err(anyhow!(
+                        "`{}` has the unsupported protocol \"{}\"",
+                        display_key_path(&port_path),
+                        other
+                    )
+                    .into())
+                }
+            };
+            port_mappings.push(json!({
+                "proto": proto,
+                "container_port": container_port,
+                "host_ip": get_str(port, "hostIP", &port_path)?.unwrap_or("0.0.0.0"),
+                "host_port": host_port,
+                "host_port_end": host_port,
+            }));
+        }
+        Ok(port_mappings)
+    }
+
+    fn privileged(
+        &mut self,
+        container: &Map<String, Value>,
+        path: &[String],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let security_context = match container.get("securityContext") {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        let path = child(path, "securityContext");
+        let security_context = as_object(security_context, &path)?;
+        self.warnings
+            .unsupported(security_context, &["privileged"], &path);
+        Ok(security_context
+            .get("privileged")
+            .and_then(Value::as_bool)
+            .unwrap_or(false))
+    }
+
+    fn pod(
+        &mut self,
+        pod_name: &str,
+        pod_spec: &Value,
+        path: &[String],
+    ) -> Result<Vec<ConvertedContainer>, Box<dyn std::error::Error>> {
+        let pod_spec = as_object(pod_spec, path)?;
+        self.warnings.unsupported(pod_spec, &POD_SPEC_FIELDS, path);
+
+        let volumes = self.volumes(pod_spec, path)?;
+        let restart_policy = self.restart_policy(pod_spec, path)?;
+        let host_network = pod_spec
+            .get("hostNetwork")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let containers = get_array(pod_spec, "containers", path)?;
+        if containers.is_empty() {
+            return Err(anyhow!("`{}` has no containers", display_key_path(path)).into());
+        }
+
+        let mut converted = Vec::with_capacity(containers.len());
+        for (i, container) in containers.iter().enumerate() {
+            let container_path = child(&child(path, "containers"), &format!("[{}]", i));
+            let container = as_object(container, &container_path)?;
+            self.warnings
+                .unsupported(container, &CONTAINER_FIELDS, &container_path);
+
+            let name = get_str(container, "name", &container_path)?
+                .ok_or_else(|| anyhow!("`{}` has no name", display_key_path(&container_path)))?;
+            let image = get_str(container, "image", &container_path)?
+                .ok_or_else(|| anyhow!("`{}` has no image", display_key_path(&container_path)))?;
+
+            // Kanto-CM has no separate entrypoint, the command and its arguments form the cmd
+            let mut cmd = Vec::new();
+            for key in ["command", "args"] {
+                for arg in get_array(container, key, &container_path)? {
+                    let arg = arg.as_str().ok_or_else(|| {
+                        anyhow!(
+                            "`{}` must be a list of strings",
+                            display_key_path(&child(&container_path, key))
+                        )
+                    })?;
+                    cmd.push(Value::String(String::from(arg)));
+                }
+            }
+
+            let mut ctr = internal_state_template()?;
+            ctr["name"] = Value::String(if containers.len() == 1 {
+                String::from(pod_name)
+            } else {
+                format!("{}-{}", pod_name, name)
+            });
+            ctr["image"]["name"] = Value::String(String::from(image));
+            ctr["config"]["cmd"] = Value::Array(cmd);
+            ctr["config"]["env"] = Value::Array(self.env(container, &container_path)?);
+            ctr["mounts"] = Value::Array(self.mounts(container, &volumes, &container_path)?);
+            ctr["host_config"]["port_mappings"] =
+                Value::Array(self.port_mappings(container, &container_path)?);
+            ctr["host_config"]["privileged"] =
+                Value::Bool(self.privileged(container, &container_path)?);
+            if host_network {
+                ctr["host_config"]["network_mode"] = Value::String(String::from("host"));
+            }
+            if let Some(policy) = restart_policy {
+                ctr["host_config"]["restart_policy"]["type"] = Value::String(String::from(policy));
+            }
+            converted.push(ConvertedContainer {
+                container: ctr,
+                depends_on: Vec::new(),
+            });
+        }
+        Ok(converted)
+    }
+}
+
+fn metadata_name(manifest: &Map<String, Value>) -> Result<&str, Box<dyn std::error::Error>> {
+    manifest
+        .get("metadata")
+        .and_then(|m| m.get("name"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("`metadata.name` is required").into())
+}
+
+/// Converts a Pod or Deployment into the internal state representation of its containers.
+/// A pod with a single container keeps the pod name, otherwise the containers are named `<pod>-<container>`.
+pub fn convert(manifest: &Value) -> Result<Conversion, Box<dyn std::error::Error>> {
+    let mut converter = Converter::default();
+    let manifest = as_object(manifest, &[])?;
+    let name = metadata_name(manifest)?;
+    converter
+        .warnings
+        .unsupported(manifest, &TOP_LEVEL_FIELDS, &[]);
+    if let Some(metadata) = manifest.get("metadata").and_then(Value::as_object) {
+        let metadata_path = [String::from("metadata")];
+        converter
+            .warnings
+            .unsupported(metadata, &METADATA_FIELDS, &metadata_path);
+    }
+    let spec_path = vec![String::from("spec")];
+    let spec = manifest
+        .get("spec")
+        .ok_or_else(|| anyhow!("`spec` is required"))?;
+
+    let containers = match manifest.get("kind").and_then(Value::as_str) {
+        Some("Pod") => converter.pod(name, spec, &spec_path)?,
+        Some("Deployment") => {
+            let deployment_spec = as_object(spec, &spec_path)?;
+            converter.warnings.unsupported(
+                deployment_spec,
+                &["template", "selector", "replicas"],
+                &spec_path,
+            );
+            match deployment_spec.get("replicas").and_then(Value::as_i64) {
+                None | Some(1) => {}
+                Some(_) => converter.warnings.warn(
+                    child(&spec_path, "replicas"),
+                    "Kanto-CM runs a single instance of each container",
+                ),
+            }
+            let template_path = child(&spec_path, "template");
+            let pod_spec = deployment_spec
+                .get("template")
+                .and_then(|t| t.get("spec"))
+                .ok_or_else(|| anyhow!("`spec.template.spec` is required"))?;
+            converter.pod(name, pod_spec, &child(&template_path, "spec"))?
+        }
+        Some(kind) => {
+            return Err(anyhow!(
+                "Unsupported Kubernetes kind \"{}\", only Pod and Deployment can be imported",
+                kind
+            )
+            .into())
+        }
+        None => return Err(anyhow!("`kind` must be a string").into()),
+    };
+    Ok(Conversion {
+        name: String::from(name),
+        containers,
+        warnings: converter.warnings.into_inner(),
+    })
+}
@@ -0,0 +1,526 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Offline manifest checks for the `validate` subcommand (for every supported `ManifestSyntax`).
+//!
+//! Unlike `manifest_parser::try_parse_manifest`, which silently falls back from one format
+//! to the other, this module reports which format was detected and every problem found:
+//! syntax and type errors (with line and column where possible), unknown or misspelled keys,
+//! and optional fields that fall back to the template defaults.
+use crate::kanto_cnt::Container;
+use crate::manifest_parser::conversion::Conversion;
+use crate::manifest_parser::{
+    self, display_key_path, ManifestFormat, ManifestSyntax, Variables, DEPLOYMENT_OPTIONS_KEY,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str_repr = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}", str_repr)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Line and column (both starting from 1) the diagnostic refers to, if known
+    pub location: Option<(usize, usize)>,
+}
+
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub path: PathBuf,
+    pub syntax: Option<ManifestSyntax>,
+    pub format: Option<ManifestFormat>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ValidationReport {
+    fn new(path: &Path) -> Self {
+        ValidationReport {
+            path: path.to_path_buf(),
+            syntax: None,
+            format: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Report for a manifest that could not even be read from disk
+    pub fn unreadable(path: &Path, error: &std::io::Error) -> Self {
+        let mut report = ValidationReport::new(path);
+        report.push(
+            Severity::Error,
+            format!("Could not read file: {}", error),
+            None,
+        );
+        report
+    }
+
+    /// Anything above informational messages is a problem (including unknown keys)
+    pub fn has_problems(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity >= Severity::Warning)
+    }
+
+    fn push(&mut self, severity: Severity, message: String, location: Option<(usize, usize)>) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message,
+            location,
+        });
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.syntax, self.format) {
+            (Some(syntax), Some(format)) => writeln!(
+                f,
+                "{}: detected {} format ({})",
+                self.path.display(),
+                format,
+                syntax
+            )?,
+            _ => writeln!(
+                f,
+                "{}: could not detect the manifest format",
+                self.path.display()
+            )?,
+        }
+        for d in &self.diagnostics {
+            match d.location {
+                Some((line, column)) => writeln!(
+                    f,
+                    "{}:{}:{}: {}: {}",
+                    self.path.display(),
+                    line,
+                    column,
+                    d.severity,
+                    d.message
+                )?,
+                None => writeln!(f, "{}: {}: {}", self.path.display(), d.severity, d.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Line and column (starting from 1) of a byte offset in the content
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Whether the text right after a key name makes it a key rather than a value:
+/// `"key":` in JSON, `key:` in YAML, `key =` or `[key]` / `[key.nested]` in TOML
+fn is_key_end(rest: &str) -> bool {
+    let rest = rest.trim_start_matches(['"', '\'']).trim_start();
+    rest.starts_with(':') || rest.starts_with('=') || rest.starts_with('.') || rest.starts_with(']')
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Finds where a (nested) key is written in the manifest by searching for each
+/// key of the path after the previous one. Array index segments are skipped.
+/// This is a best-effort text search that works the same for all manifest syntaxes.
+fn locate_key(content: &str, path: &[String]) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    let mut key_start = None;
+    for key in path.iter().filter(|k| !k.starts_with('[')) {
+        loop {
+            let found = content[offset..].find(key.as_str())? + offset;
+            offset = found + key.len();
+            let preceded_by_identifier = matches!(
+                content[..found].chars().next_back(),
+                Some(c) if is_identifier_char(c)
+            );
+            let followed_by_identifier = matches!(
+                content[offset..].chars().next(),
+                Some(c) if is_identifier_char(c)
+            );
+            if !preceded_by_identifier && !followed_by_identifier && is_key_end(&content[offset..])
+            {
+                // Point at the opening quote of quoted keys
+                let quoted = matches!(content[..found].chars().next_back(), Some('"' | '\''));
+                key_start = Some(if quoted { found - 1 } else { found });
+                break;
+            }
+        }
+    }
+    key_start.map(|o| line_column(content, o))
+}
+
+/// Collects the keys present in `original` that are missing from `known`
+/// (i.e. were dropped when the manifest was parsed into the typed structures).
+fn unknown_keys(
+    original: &Value,
+    known: &Value,
+    path: &mut Vec<String>,
+    found: &mut Vec<Vec<String>>,
+) {
+    match (original, known) {
+        (Value::Object(original), Value::Object(known)) => {
+            for (key, value) in original {
+                path.push(key.clone());
+                match known.get(key) {
+                    Some(known_value) => unknown_keys(value, known_value, path, found),
+                    None => found.push(path.clone()),
+                }
+                path.pop();
+            }
+        }
+        (Value::Array(original), Value::Array(known)) => {
+            for (i, (value, known_value)) in original.iter().zip(known).enumerate() {
+                path.push(format!("[{}]", i));
+                unknown_keys(value, known_value, path, found);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the keys of the template missing from the manifest. If a whole object is missing,
+/// only the object is reported and not each of its keys.
+fn defaulted_keys(
+    manifest: &Value,
+    template: &Value,
+    path: &mut Vec<String>,
+    found: &mut Vec<(Vec<String>, Value)>,
+) {
+    if let (Value::Object(manifest), Value::Object(template)) = (manifest, template) {
+        for (key, default) in template {
+            path.push(key.clone());
+            match manifest.get(key) {
+                Some(value) => defaulted_keys(value, default, path, found),
+                None => found.push((path.clone(), default.clone())),
+            }
+            path.pop();
+        }
+    }
+}
+
+fn report_unknown_keys(
+    report: &mut ValidationReport,
+    content: &str,
+    original: &Value,
+    known: &Value,
+    prefix: &[String],
+) {
+    let mut found = Vec::new();
+    unknown_keys(original, known, &mut prefix.to_vec(), &mut found);
+    for key_path in found {
+        report.push(
+            Severity::Warning,
+            format!("Unknown key `{}` is ignored", display_key_path(&key_path)),
+            locate_key(content, &key_path),
+        );
+    }
+}
+
+/// Deserializes the document like `manifest_parser` does, but keeps the position of the error.
+/// The parsers append the location to their messages, it is part of the diagnostic already.
+fn deserialize_located<T: DeserializeOwned>(
+    content: &str,
+    syntax: ManifestSyntax,
+) -> Result<T, (String, Option<(usize, usize)>)> {
+    match syntax {
+        ManifestSyntax::Json => serde_json::from_str(content).map_err(|e| {
+            let location = format!(" at line {} column {}", e.line(), e.column());
+            let message = e.to_string();
+            let message = message.strip_suffix(&location).unwrap_or(&message);
+            (String::from(message), Some((e.line(), e.column())))
+        }),
+        ManifestSyntax::Yaml => serde_yaml::from_str(content).map_err(|e| {
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or(&message);
+            let location = e.location().map(|l| (l.line(), l.column()));
+            (String::from(message), location)
+        }),
+        ManifestSyntax::Toml => toml::from_str(content).map_err(|e| {
+            let location = e.span().map(|span| line_column(content, span.start));
+            (String::from(e.message()), location)
+        }),
+    }
+}
+
+fn validate_variables(
+    report: &mut ValidationReport,
+    content: &str,
+    manifest: &mut Value,
+    vars: &Variables,
+) {
+    match manifest_parser::substitute_collecting_undefined(manifest, vars) {
+        Ok(undefined) => {
+            for name in undefined {
+                let reference = format!("${{{}", name);
+                let location = content
+                    .find(&reference)
+                    .map(|offset| line_column(content, offset));
+                report.push(
+                    Severity::Error,
+                    format!("Undefined variable `{}` without a default", name),
+                    location,
+                );
+            }
+        }
+        Err(e) => report.push(Severity::Error, e.to_string(), None),
+    }
+}
+
+fn validate_deployment_options(report: &mut ValidationReport, content: &str, manifest: &mut Value) {
+    let original = manifest.get(DEPLOYMENT_OPTIONS_KEY).cloned();
+    let location = locate_key(content, &[String::from(DEPLOYMENT_OPTIONS_KEY)]);
+    match manifest_parser::take_deployment_options(manifest) {
+        Ok(options) => {
+            if let (Some(original), Ok(known)) = (original, serde_json::to_value(&options)) {
+                let prefix = [String::from(DEPLOYMENT_OPTIONS_KEY)];
+                report_unknown_keys(report, content, &original, &known, &prefix);
+            }
+        }
+        Err(e) => report.push(
+            Severity::Error,
+            format!("Invalid `{}` options: {}", DEPLOYMENT_OPTIONS_KEY, e),
+            location,
+        ),
+    }
+}
+
+fn validate_internal(
+    report: &mut ValidationReport,
+    content: &str,
+    syntax: ManifestSyntax,
+    manifest: &Value,
+) {
+    // Check the manifest with the variables substituted, as it is deployed
+    let container = match Container::deserialize(manifest) {
+        Ok(c) => c,
+        Err(e) => {
+            // Parse the raw text again so that serde can point at the exact line and column
+            match deserialize_located::<Container>(content, syntax) {
+                Err((message, location)) => report.push(Severity::Error, message, location),
+                Ok(_) => report.push(Severity::Error, e.to_string(), None),
+            }
+            return;
+        }
+    };
+    if let Ok(known) = serde_json::to_value(&container) {
+        report_unknown_keys(report, content, manifest, &known, &[]);
+    }
+}
+
+fn validate_init_dir(report: &mut ValidationReport, content: &str, manifest: &Value) {
+    let fields = match manifest.as_object() {
+        Some(f) => f,
+        None => return,
+    };
+    let known_keys = manifest_parser::MANDATORY_INIT_DIR_KEYS
+        .iter()
+        .chain(manifest_parser::OPTIONAL_INIT_DIR_KEYS.iter());
+
+    for key in fields.keys() {
+        if !known_keys.clone().any(|(k, _)| k == key) {
+            let key_path = [key.clone()];
+            report.push(
+                Severity::Warning,
+                format!("Unknown key `{}` is ignored", key),
+                locate_key(content, &key_path),
+            );
+        }
+    }
+    for (key, _) in manifest_parser::MANDATORY_INIT_DIR_KEYS {
+        if !fields.contains_key(key) {
+            report.push(
+                Severity::Error,
+                format!("Missing mandatory key `{}`", key),
+                None,
+            );
+        }
+    }
+    for (key, _) in manifest_parser::OPTIONAL_INIT_DIR_KEYS {
+        if !fields.contains_key(key) {
+            report.push(
+                Severity::Info,
+                format!("`{}` is not set, using the template default", key),
+                None,
+            );
+        }
+    }
+
+    let (expanded, template) = match (
+        manifest_parser::expand_container_manifest(manifest),
+        manifest_parser::internal_state_template(),
+    ) {
+        (Ok(e), Ok(t)) => (e, t),
+        _ => return,
+    };
+
+    // Check each section on its own so that type errors can be attributed to it
+    let mut sections_valid = true;
+    for (init_dir_key, internal_key) in known_keys.clone() {
+        let section = match expanded.get(init_dir_key) {
+            Some(s) if fields.contains_key(*init_dir_key) => s,
+            _ => continue,
+        };
+        let mut candidate = template.clone();
+        candidate[*internal_key] = section.clone();
+        if let Err(e) = Container::deserialize(&candidate) {
+            sections_valid = false;
+            report.push(
+                Severity::Error,
+                format!("`{}`: {}", init_dir_key, e),
+                locate_key(content, &[String::from(*init_dir_key)]),
+            );
+        }
+    }
+    if !sections_valid {
+        return;
+    }
+
+    let converted = manifest_parser::map_to_internal_state_manifest(expanded)
+        .ok()
+        .and_then(|v| serde_json::from_value::<Container>(v).ok())
+        .and_then(|c| serde_json::to_value(c).ok());
+    let converted = match converted {
+        Some(c) => c,
+        None => return,
+    };
+
+    for (init_dir_key, internal_key) in known_keys {
+        let (original, known) = match (fields.get(*init_dir_key), converted.get(*internal_key)) {
+            (Some(o), Some(k)) => (o, k),
+            _ => continue,
+        };
+        let prefix = [String::from(*init_dir_key)];
+        report_unknown_keys(report, content, original, known, &prefix);
+
+        // Nested options missing from the manifest are merged from the template
+        if let Some(template_section) = template.get(*internal_key) {
+            let mut defaulted = Vec::new();
+            defaulted_keys(
+                original,
+                template_section,
+                &mut prefix.to_vec(),
+                &mut defaulted,
+            );
+            for (key_path, default) in defaulted {
+                report.push(
+                    Severity::Info,
+                    format!(
+                        "`{}` is not set, using the template default {}",
+                        display_key_path(&key_path),
+                        default
+                    ),
+                    None,
+                );
+            }
+        }
+    }
+}
+
+fn validate_conversion(
+    report: &mut ValidationReport,
+    content: &str,
+    conversion: Result<Conversion, Box<dyn std::error::Error>>,
+) {
+    let conversion = match conversion {
+        Ok(c) => c,
+        Err(e) => {
+            report.push(Severity::Error, e.to_string(), None);
+            return;
+        }
+    };
+    for warning in conversion.warnings {
+        let location = locate_key(content, &warning.field);
+        report.push(Severity::Warning, warning.to_string(), location);
+    }
+    for converted in conversion.containers {
+        if let Err(e) = Container::deserialize(&converted.container) {
+            report.push(Severity::Error, e.to_string(), None);
+        }
+    }
+}
+
+/// Runs all checks on the manifest contents. Never connects to Kanto-CM.
+pub fn validate_manifest(path: &Path, content: &str, vars: &Variables) -> ValidationReport {
+    let mut report = ValidationReport::new(path);
+
+    let syntax = match ManifestSyntax::from_path(path) {
+        Some(s) => s,
+        None => {
+            let message = format!(
+                "Unsupported file type, expected one of the extensions: {}",
+                ManifestSyntax::EXTENSIONS.join(", ")
+            );
+            report.push(Severity::Error, message, None);
+            return report;
+        }
+    };
+    report.syntax = Some(syntax);
+
+    let mut manifest: Value = match deserialize_located(content, syntax) {
+        Ok(m) => m,
+        Err((message, location)) => {
+            report.push(Severity::Error, message, location);
+            return report;
+        }
+    };
+    validate_variables(&mut report, content, &mut manifest, vars);
+    validate_deployment_options(&mut report, content, &mut manifest);
+
+    report.format = manifest_parser::detect_format(&manifest);
+    match report.format {
+        Some(ManifestFormat::Internal) => {
+            validate_internal(&mut report, content, syntax, &manifest)
+        }
+        Some(ManifestFormat::InitDir) => validate_init_dir(&mut report, content, &manifest),
+        Some(ManifestFormat::Kubernetes) => {
+            let conversion = manifest_parser::kubernetes::convert(&manifest);
+            validate_conversion(&mut report, content, conversion)
+        }
+        Some(ManifestFormat::Compose) => {
+            let conversion = manifest_parser::compose::convert(&manifest);
+            validate_conversion(&mut report, content, conversion)
+        }
+        None => report.push(
+            Severity::Error,
+            String::from(
+                "Neither `name` (internal format) nor `container_name` (init-dir format) is set",
+            ),
+            None,
+        ),
+    }
+    report
+}
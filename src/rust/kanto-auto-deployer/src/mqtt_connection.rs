@@ -0,0 +1,188 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Connection options (TLS, credentials, client id) shared by all MQTT clients of the auto-deployer.
+//!
+//! The certificates and keys are loaded upfront, so that a wrong path or an invalid PEM file is
+//! reported once at startup, instead of as a failing connection attempt every few seconds.
+use crate::MQTTconfig;
+use anyhow::{anyhow, Result};
+use rumqttc::tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rumqttc::{ConnectionError, MqttOptions, TlsConfiguration, Transport};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A password that is not printed along with the rest of the configuration
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Secret(String::from(s)))
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path).map_err(|e| anyhow!("Could not open {:?}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| anyhow!("{:?} is not a valid PEM file: {}", path, e))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {:?}", path));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "No private key found in {:?}, expected an unencrypted PKCS#1, PKCS#8 or SEC1 key",
+                path
+            )
+        })
+}
+
+fn root_certificates(ca_file: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in read_certificates(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| anyhow!("Invalid CA certificate in {:?}: {}", path, e))?;
+            }
+        }
+        None => {
+            let native_certs = rustls_native_certs::load_native_certs()
+                .map_err(|e| anyhow!("Could not load the system CA certificates: {}", e))?;
+            for cert in native_certs {
+                // Some system stores contain certificates rustls can not parse, skip those
+                let _ = roots.add(&Certificate(cert.0));
+            }
+            if roots.is_empty() {
+                return Err(anyhow!(
+                    "No usable system CA certificates found, set --mqtt-ca-file"
+                ));
+            }
+        }
+    }
+    Ok(roots)
+}
+
+fn tls_config(config: &MQTTconfig) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certificates(config.ca_file.as_deref())?);
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(read_certificates(cert)?, read_private_key(key)?)
+            .map_err(|e| {
+                anyhow!(
+                    "Client certificate {:?} and key {:?} can not be used: {}",
+                    cert,
+                    key,
+                    e
+                )
+            }),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(anyhow!(
+            "--mqtt-client-cert and --mqtt-client-key have to be set together"
+        )),
+    }
+}
+
+/// Options for a client connecting to the configured broker. The suffix keeps the client ids
+/// of several clients of this instance apart.
+pub fn mqtt_options(config: &MQTTconfig, client_id_suffix: &str) -> Result<MqttOptions> {
+    let client_id = format!("{}{}", config.client_id, client_id_suffix);
+    let mut options = MqttOptions::new(client_id, &config.ip, config.port);
+    options.set_keep_alive(Duration::from_secs(5));
+    if config.tls_enabled() {
+        let tls = tls_config(config).map_err(|e| anyhow!("Invalid MQTT TLS settings: {}", e))?;
+        options.set_transport(Transport::Tls(TlsConfiguration::Rustls(Arc::new(tls))));
+    }
+    match (&config.username, &config.password) {
+        (Some(username), password) => {
+            let password = password.as_ref().map(|p| p.0.as_str()).unwrap_or_default();
+            options.set_credentials(username, password);
+        }
+        (None, Some(_)) => return Err(anyhow!("--mqtt-password requires --mqtt-username")),
+        (None, None) => {}
+    }
+    Ok(options)
+}
+
+/// The TLS error behind a connection error, which may be wrapped in (several) I/O errors
+fn tls_error(e: &ConnectionError) -> Option<&rustls::Error> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(err) = source {
+        if let Some(tls_err) = err.downcast_ref::<rustls::Error>() {
+            return Some(tls_err);
+        }
+        // The source of an I/O error skips the error it wraps, so unwrap it explicitly
+        let wrapped = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io_err| io_err.get_ref());
+        if let Some(tls_err) = wrapped.and_then(|w| w.downcast_ref::<rustls::Error>()) {
+            return Some(tls_err);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// Adds a hint on which option is likely wrong to the errors caused by certificates or credentials
+pub fn describe_error(e: &ConnectionError) -> String {
+    match (tls_error(e), e) {
+        (Some(rustls::Error::AlertReceived(alert)), _) => format!(
+            "The broker rejected the TLS handshake ({:?}), check --mqtt-client-cert and --mqtt-client-key",
+            alert
+        ),
+        (Some(tls_err), _) => format!(
+            "TLS handshake with the broker failed ({}), check that --mqtt-ca-file contains the CA of the broker certificate and that --mqtt-broker-host matches it",
+            tls_err
+        ),
+        (None, ConnectionError::ConnectionRefused(code)) => format!(
+            "The broker refused the connection ({:?}), check --mqtt-username, --mqtt-password and --mqtt-client-id",
+            code
+        ),
+        _ => e.to_string(),
+    }
+}
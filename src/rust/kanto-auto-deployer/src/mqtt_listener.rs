@@ -10,38 +10,32 @@
 // *
 // * SPDX-License-Identifier: Apache-2.0
 // ********************************************************************************
+use crate::kad_lock;
+use crate::mqtt_connection;
+use crate::signals;
 use crate::CliArgs;
 use anyhow::{anyhow, Result};
-use lazy_static::lazy_static;
-use rumqttc::{self, Client, Event::Incoming, MqttOptions, Packet::Publish, QoS};
+use rumqttc::{self, Client, Event::Incoming, Packet::Publish, QoS};
 use serde::{self, Deserialize, Serialize};
 use serde_json;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-static SERVICE_ID: &str = "kanto_auto_deployer";
 // We let CUA take over when it has identified what it should do
 static VUM_STATUS_IDENTIFIED: &str = "IDENTIFIED";
 static RECONNECT_TIMEOUT: u64 = 2;
-
-lazy_static! {
-    static ref LOCK_PATH: PathBuf = {
-        match std::option_env!("KAD_LOCK_PATH") {
-            Some(p) => PathBuf::from(p),
-            None => PathBuf::from("/var/lib/kanto-auto-deployer/KAD.enabled"),
-        }
-    };
-}
+static COMMAND_ENABLE: &str = "enable";
 
 #[derive(Serialize, Deserialize, Debug)]
-struct VUMEnvelope<T> {
-    #[serde(alias = "activityId")]
-    activity_id: String,
-    timestamp: u64,
-    payload: T,
+pub(crate) struct VUMEnvelope<T> {
+    #[serde(rename = "activityId", alias = "activity_id")]
+    pub activity_id: String,
+    /// Milliseconds since the Unix epoch, blueprint-selector may leave it out
+    #[serde(default)]
+    pub timestamp: u64,
+    pub payload: T,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -52,35 +46,37 @@ struct FeedbackPayload {
 
 type FeedbackMsg = VUMEnvelope<FeedbackPayload>;
 
-fn kad_enabled(lock: &Path) -> bool {
-    lock.exists() && lock.is_file()
+#[derive(Serialize, Deserialize, Debug)]
+struct CommandPayload {
+    /// Only "enable" is supported
+    command: String,
+    /// Free text recorded in the audit log, e.g. who asked for it
+    #[serde(default)]
+    reason: Option<String>,
 }
 
-fn disable_kad(lock_path: &Path) -> Result<()> {
-    let mut disabled_lock_path = lock_path.to_path_buf();
+type CommandMsg = VUMEnvelope<CommandPayload>;
 
-    if !kad_enabled(lock_path) {
+/// Re-enables KAD on request
+fn handle_command_payload(payload: &[u8], lock_path: &Path) -> Result<()> {
+    let msg = serde_json::from_slice::<CommandMsg>(payload)?;
+    if msg.payload.command != COMMAND_ENABLE {
         return Err(anyhow!(
-            "Lock {:?} is not a regular file or does not exist",
-            lock_path
+            "Unsupported command \"{}\", expected \"{COMMAND_ENABLE}\"",
+            msg.payload.command
         ));
     }
-    if !disabled_lock_path.set_extension("disabled") {
-        return Err(anyhow!(
-            "Could not change extension of {:?} to *.disabled",
-            lock_path
-        ));
+    let mut source = format!("MQTT command (activity {})", msg.activity_id);
+    if let Some(reason) = &msg.payload.reason {
+        source.push_str(&format!(": {}", reason));
+    }
+    if !kad_lock::enable(lock_path, &source)? {
+        log::info!("KAD is already enabled, ignoring MQTT command");
     }
-    fs::rename(lock_path, disabled_lock_path)?;
-
     Ok(())
 }
 
-fn handle_mqtt_payload(
-    payload: &[u8],
-    lock_path: &Path,
-    thread_terminate_flag: &AtomicBool,
-) -> Result<()> {
+fn handle_mqtt_payload(payload: &[u8], lock_path: &Path) -> Result<()> {
     // Listen when VUM starts "identifying" what actions it should take.
     let terminate_flag_mqtt = serde_json::from_slice::<FeedbackMsg>(payload)?
         .payload
@@ -92,19 +88,29 @@ fn handle_mqtt_payload(
             "Expected status:\"{VUM_STATUS_IDENTIFIED}\" for status"
         ));
     }
-    disable_kad(lock_path)?;
-
-    // Will only be reached if everything above was successful
-    thread_terminate_flag.store(true, Ordering::Relaxed);
+    if !kad_lock::disable(lock_path, "VUM desired state feedback")? {
+        return Err(anyhow!(
+            "Lock {:?} is not a regular file or does not exist",
+            lock_path
+        ));
+    }
     Ok(())
 }
 
-fn try_mqtt_reconnect(timeout: &mut Duration, client: &mut Client, topic: &str, delta: Duration) {
+fn try_mqtt_reconnect(
+    timeout: &mut Duration,
+    client: &mut Client,
+    topics: &[&str],
+    delta: Duration,
+) {
     log::error!(
         "MQTT connection lost, trying to re-subscribe in {} s",
         timeout.as_secs()
     );
-    if let Err(e) = client.try_subscribe(topic, QoS::ExactlyOnce) {
+    let resubscribed = topics
+        .iter()
+        .try_for_each(|topic| client.try_subscribe(*topic, QoS::ExactlyOnce));
+    if let Err(e) = resubscribed {
         log::debug!("Failed to resubscribe: {e}");
         *timeout += delta;
         std::thread::sleep(*timeout);
@@ -119,42 +125,104 @@ pub fn mqtt_main(cli_config: Arc<CliArgs>, thread_terminate_flag: &AtomicBool) -
         "Trying to start MQTT connection with options {:?}",
         &cli_config.mqtt
     );
+    let lock_path: &Path = &cli_config.lock_path;
+    let command_topic = cli_config.mqtt.command_topic.as_deref();
 
-    if !kad_enabled(&LOCK_PATH) {
-        log::error!(
-            "The lock at {:?} does not exist, but KAD was started with the MQTT client \
-        option. MQTT listener will not be started, but KAD will still run in daemon mode. \
-        If running as a system service this might mean KAD has previously seen the desired \
-        state MQTT message and has auto-disabled itself to avoid conflicts with CUA and using \
-        it might lead to unexpected behavior.",
-            *LOCK_PATH
-        );
-        return Ok(());
+    let mut enabled = kad_lock::kad_enabled(lock_path);
+    match (enabled, command_topic) {
+        (true, _) => {
+            log::info!("MQTT for daemon mode enabled. Will auto-disable whenever VUM takes over.")
+        }
+        (false, Some(topic)) => log::warn!(
+            "The lock at {:?} does not exist, KAD will not auto-disable while it is not enabled. \
+            Listening for the command to re-enable it on {:?}",
+            lock_path,
+            topic
+        ),
+        (false, None) => {
+            log::error!(
+                "The lock at {:?} does not exist, but KAD was started with the MQTT client \
+            option. MQTT listener will not be started, but KAD will still run in daemon mode. \
+            If running as a system service this might mean KAD has previously seen the desired \
+            state MQTT message and has auto-disabled itself to avoid conflicts with CUA and using \
+            it might lead to unexpected behavior.",
+                lock_path
+            );
+            return Ok(());
+        }
     }
-    log::info!("MQTT for daemon mode enabled. Will auto-disable whenever VUM takes over.");
 
-    let mut mqttoptions = MqttOptions::new(SERVICE_ID, &cli_config.mqtt.ip, cli_config.mqtt.port);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    let mqttoptions = match mqtt_connection::mqtt_options(&cli_config.mqtt, "") {
+        Ok(o) => o,
+        Err(e) => {
+            log::error!("MQTT listener will not be started: {e}");
+            return Err(e);
+        }
+    };
     let delta = Duration::from_secs(RECONNECT_TIMEOUT);
     let mut timeout = delta;
 
     let (mut client, mut connection) = Client::new(mqttoptions.clone(), 10);
-    client.subscribe(&cli_config.mqtt.topic, QoS::ExactlyOnce)?;
+    signals::on_shutdown({
+        let mut client = client.clone();
+        move || {
+            if let Err(e) = client.try_disconnect() {
+                log::debug!("Could not disconnect the MQTT listener: {e}");
+            }
+        }
+    });
+    let mut topics: Vec<&str> = Vec::new();
+    if let Some(topic) = command_topic {
+        client.subscribe(topic, QoS::ExactlyOnce)?;
+        topics.push(topic);
+    }
+    if enabled {
+        client.subscribe(&cli_config.mqtt.topic, QoS::ExactlyOnce)?;
+        topics.push(&cli_config.mqtt.topic);
+    }
 
     for notification in connection.iter() {
-        if let Ok(msg) = notification {
+        match notification {
             // We only care about incoming messages
-            if let Incoming(Publish(pub_msg)) = msg {
-                match handle_mqtt_payload(&pub_msg.payload, &LOCK_PATH, thread_terminate_flag) {
+            Ok(Incoming(Publish(pub_msg))) if Some(pub_msg.topic.as_str()) == command_topic => {
+                match handle_command_payload(&pub_msg.payload, lock_path) {
+                    Err(e) => log::warn!("Ignoring MQTT command: {e}"),
+                    Ok(()) if !enabled => {
+                        // Start watching for VUM again, and deploy what changed in the meantime
+                        enabled = true;
+                        client.subscribe(&cli_config.mqtt.topic, QoS::ExactlyOnce)?;
+                        topics.push(&cli_config.mqtt.topic);
+                        signals::reload();
+                    }
+                    Ok(()) => {}
+                }
+            }
+            Ok(Incoming(Publish(pub_msg))) => {
+                match handle_mqtt_payload(&pub_msg.payload, lock_path) {
                     Err(e) => {
                         // Message with status VUM_STATUS_IDENTIFYING not found, continue listening
                         log::debug!("MQTT payload handling error: {e}")
                     }
-                    Ok(_) => return Ok(()), // Desired state message found, exit MQTT thread
+                    // Keep listening for the command to re-enable KAD, the daemon pauses meanwhile
+                    Ok(_) if command_topic.is_some() => {
+                        log::warn!("VUM took over, not deploying until KAD is enabled again");
+                        enabled = false;
+                        client.unsubscribe(&cli_config.mqtt.topic)?;
+                        topics.retain(|t| *t != cli_config.mqtt.topic);
+                    }
+                    Ok(_) => {
+                        // Desired state message found, stop the daemon and exit MQTT thread
+                        thread_terminate_flag.store(true, Ordering::Relaxed);
+                        return Ok(());
+                    }
                 }
             }
-        } else {
-            try_mqtt_reconnect(&mut timeout, &mut client, &cli_config.mqtt.topic, delta);
+            Ok(_) => {}
+            Err(_) if signals::shutting_down() => break,
+            Err(e) => {
+                log::error!("MQTT error: {}", mqtt_connection::describe_error(&e));
+                try_mqtt_reconnect(&mut timeout, &mut client, &topics, delta);
+            }
         }
     }
 
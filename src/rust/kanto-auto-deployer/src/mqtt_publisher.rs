@@ -0,0 +1,194 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Publishes what the auto-deployer did as MQTT events.
+//!
+//! Every deployment run (the initial deployment, or a manifest change in daemon mode) gets its
+//! own activity id. One event is published per container (or per manifest that could not be
+//! parsed), followed by a summary of the run.
+use crate::mqtt_connection;
+use crate::mqtt_listener::VUMEnvelope;
+use crate::status_report::{LastAction, ManifestStatus, StatusListener};
+use crate::MQTTconfig;
+use anyhow::Result;
+use rumqttc::{Client, ConnectionError, Event, Outgoing, QoS};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static CLIENT_ID_SUFFIX: &str = "_events";
+static RECONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait for the queued events to be sent when shutting down
+static FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum DeploymentEvent<'a> {
+    /// A container was deployed or removed, or its manifest could not be deployed at all
+    Manifest {
+        manifest: &'a PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        container: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<&'a str>,
+        action: LastAction,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    /// Number of containers per action in the run
+    Summary {
+        manifests: usize,
+        actions: BTreeMap<LastAction, usize>,
+    },
+}
+
+pub struct EventPublisher {
+    topic: String,
+    client: Mutex<Client>,
+    closing: Arc<AtomicBool>,
+    /// Disambiguates the activity ids of runs within the same millisecond
+    runs: AtomicU64,
+    done: Mutex<mpsc::Receiver<()>>,
+}
+
+pub(crate) fn timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn events<'a>(manifests: &'a [(PathBuf, ManifestStatus)]) -> Vec<DeploymentEvent<'a>> {
+    let mut events = Vec::new();
+    let mut actions: BTreeMap<LastAction, usize> = BTreeMap::new();
+    for (path, status) in manifests {
+        if let Some(e) = &status.error {
+            *actions.entry(LastAction::Failed).or_default() += 1;
+            events.push(DeploymentEvent::Manifest {
+                manifest: path,
+                container: None,
+                id: None,
+                action: LastAction::Failed,
+                error: Some(e),
+            });
+        }
+        for c in &status.containers {
+            *actions.entry(c.action).or_default() += 1;
+            events.push(DeploymentEvent::Manifest {
+                manifest: path,
+                container: Some(&c.name),
+                id: c.id.as_deref(),
+                action: c.action,
+                error: c.error.as_deref(),
+            });
+        }
+    }
+    events.push(DeploymentEvent::Summary {
+        manifests: manifests.len(),
+        actions,
+    });
+    events
+}
+
+impl EventPublisher {
+    /// Connects to the broker in the background, events are queued until the connection is up
+    pub fn start(config: &MQTTconfig, topic: &str) -> Result<Arc<Self>> {
+        let mqttoptions = mqtt_connection::mqtt_options(config, CLIENT_ID_SUFFIX)?;
+        let (client, mut connection) = Client::new(mqttoptions, 100);
+        let (done_tx, done_rx) = mpsc::channel();
+        let closing = Arc::new(AtomicBool::new(false));
+
+        thread::spawn({
+            let closing = Arc::clone(&closing);
+            move || {
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(ConnectionError::RequestsDone) => break,
+                        Err(e) => {
+                            if closing.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            log::error!(
+                                "MQTT events connection error: {}",
+                                mqtt_connection::describe_error(&e)
+                            );
+                            thread::sleep(RECONNECT_TIMEOUT);
+                        }
+                    }
+                }
+                let _ = done_tx.send(());
+            }
+        });
+
+        Ok(Arc::new(EventPublisher {
+            topic: String::from(topic),
+            client: Mutex::new(client),
+            closing,
+            runs: AtomicU64::new(0),
+            done: Mutex::new(done_rx),
+        }))
+    }
+
+    fn publish(&self, envelope: &VUMEnvelope<DeploymentEvent>) -> Result<()> {
+        let payload = serde_json::to_vec(envelope)?;
+        // Never block a deployment on the broker, rather drop the event
+        self.client
+            .lock()
+            .unwrap()
+            .try_publish(&self.topic, QoS::AtLeastOnce, false, payload)?;
+        Ok(())
+    }
+
+    /// Sends the queued events and disconnects, waiting at most `FLUSH_TIMEOUT`
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+        if let Err(e) = self.client.lock().unwrap().try_disconnect() {
+            log::debug!("Could not disconnect MQTT events client: {e}");
+            return;
+        }
+        if self
+            .done
+            .lock()
+            .unwrap()
+            .recv_timeout(FLUSH_TIMEOUT)
+            .is_err()
+        {
+            log::warn!("Timed out sending the deployment events over MQTT");
+        }
+    }
+}
+
+impl StatusListener for EventPublisher {
+    fn deployed(&self, manifests: &[(PathBuf, ManifestStatus)]) {
+        let timestamp = timestamp_millis();
+        let run = self.runs.fetch_add(1, Ordering::Relaxed);
+        let activity_id = format!("kanto-auto-deployer-{}-{}", timestamp, run);
+        for event in events(manifests) {
+            let envelope = VUMEnvelope {
+                activity_id: activity_id.clone(),
+                timestamp,
+                payload: event,
+            };
+            if let Err(e) = self.publish(&envelope) {
+                log::warn!("Could not publish deployment event over MQTT: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Record of the containers managed by the auto-deployer.
+//!
+//! Kanto-CM containers have no labels, so the containers created (or taken over) by the
+//! auto-deployer are kept in a file next to the status file, with the manifest they come from.
+//! A container is owned only as long as its id matches, a container re-created by hand under the
+//! same name is not. Containers that are not owned are never recreated, started or removed,
+//! unless forced or adopted.
+use crate::manifest_parser::StopSettings;
+use crate::status_report;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Written next to the status file
+static OWNERSHIP_FILE: &str = "ownership.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OwnedContainer {
+    pub id: String,
+    /// Manifest the container was deployed from
+    pub manifest: PathBuf,
+    /// SHA-256 of the manifest content the container was deployed from, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// RFC 3339 time of the last deployment
+    pub timestamp: String,
+    /// How the container is stopped, as its manifest may be gone by the time it is removed
+    #[serde(default)]
+    pub stop: StopSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct OwnershipRecord {
+    /// By container name
+    #[serde(default)]
+    containers: BTreeMap<String, OwnedContainer>,
+}
+
+pub fn ownership_file(status_file: &Path) -> PathBuf {
+    status_file.with_file_name(OWNERSHIP_FILE)
+}
+
+pub struct Ownership {
+    path: PathBuf,
+    record: Mutex<OwnershipRecord>,
+}
+
+impl Ownership {
+    /// Continues the record in the file, or starts an empty one if it does not exist (yet)
+    pub fn open(path: &Path) -> Self {
+        let record = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str(&content)?));
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                if path.exists() {
+                    log::warn!("Ignoring unreadable ownership file {:?}: {}", path, e);
+                }
+                OwnershipRecord::default()
+            }
+        };
+        Ownership {
+            path: path.to_path_buf(),
+            record: Mutex::new(record),
+        }
+    }
+
+    /// Whether the container with this name and id was created by the auto-deployer
+    pub fn owns(&self, name: &str, id: &str) -> bool {
+        matches!(self.record.lock().unwrap().containers.get(name), Some(c) if c.id == id)
+    }
+
+    /// The containers recorded as owned, by name
+    pub fn owned(&self) -> BTreeMap<String, OwnedContainer> {
+        self.record.lock().unwrap().containers.clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<OwnedContainer> {
+        self.record.lock().unwrap().containers.get(name).cloned()
+    }
+
+    pub fn record(
+        &self,
+        name: &str,
+        id: &str,
+        manifest: &Path,
+        content_hash: Option<String>,
+        stop: StopSettings,
+    ) {
+        let owned = OwnedContainer {
+            id: String::from(id),
+            manifest: manifest.to_path_buf(),
+            content_hash,
+            timestamp: status_report::now(),
+            stop,
+        };
+        let mut record = self.record.lock().unwrap();
+        record.containers.insert(String::from(name), owned);
+        self.save(&record);
+    }
+
+    pub fn forget(&self, name: &str) {
+        let mut record = self.record.lock().unwrap();
+        if record.containers.remove(name).is_some() {
+            self.save(&record);
+        }
+    }
+
+    /// Failures are only logged, as for the status report
+    fn save(&self, record: &OwnershipRecord) {
+        let result = serde_json::to_string_pretty(record)
+            .map_err(anyhow::Error::from)
+            .and_then(|c| status_report::write_atomically(&self.path, &c));
+        if let Err(e) = result {
+            log::warn!("Could not write ownership file {:?}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Optional readiness wait after a container was started.
+//!
+//! A successful StartContainer call only means the task was spawned. Here the container
+//! state is polled until it has been running for a "settle time", so that containers that
+//! crash right after starting are reported as failed deployments.
+use crate::{kanto, CmClient};
+use anyhow::{anyhow, Result};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+static READINESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadinessCheck {
+    /// For how long the container has to be running without interruption
+    pub settle_time: Duration,
+    /// Maximum time to wait for the container to settle
+    pub timeout: Duration,
+}
+
+pub async fn wait_until_ready(
+    _client: &mut CmClient,
+    name: &str,
+    id: &str,
+    check: &ReadinessCheck,
+) -> Result<()> {
+    log::info!(
+        "Waiting for [{}] to be running for {} s",
+        name,
+        check.settle_time.as_secs()
+    );
+    let deadline = Instant::now() + check.timeout;
+    let mut running_since: Option<Instant> = None;
+    let mut initial_restart_count: Option<i64> = None;
+
+    loop {
+        let _r = tonic::Request::new(kanto::GetContainerRequest {
+            id: String::from(id),
+        });
+        let container = _client
+            .get(_r)
+            .await?
+            .into_inner()
+            .container
+            .ok_or_else(|| anyhow!("[{}] disappeared while waiting for it to be ready", name))?;
+        let state = container.state.unwrap_or_default();
+
+        if state.oom_killed {
+            return Err(anyhow!("[{}] was killed as it ran out of memory", name));
+        }
+        if state.dead || (state.exited && state.exit_code != 0) {
+            return Err(anyhow!(
+                "[{}] exited with code {}: {}",
+                name,
+                state.exit_code,
+                state.error
+            ));
+        }
+        let restarts =
+            container.restart_count - *initial_restart_count.get_or_insert(container.restart_count);
+        if restarts > 0 {
+            return Err(anyhow!(
+                "[{}] is crash-looping, it was restarted {} time(s) while waiting for it to be ready",
+                name,
+                restarts
+            ));
+        }
+
+        let now = Instant::now();
+        if state.running {
+            let since = *running_since.get_or_insert(now);
+            if now.duration_since(since) >= check.settle_time {
+                log::info!("[{}] is ready", name);
+                return Ok(());
+            }
+        } else if state.exited {
+            // Exited with code 0 and not restarted by its policy, i.e. a completed one-off task
+            log::info!("[{}] has completed successfully", name);
+            return Ok(());
+        } else {
+            running_since = None;
+        }
+
+        if now >= deadline {
+            return Err(anyhow!(
+                "[{}] did not become ready within {} s (status: \"{}\")",
+                name,
+                check.timeout.as_secs(),
+                state.status
+            ));
+        }
+        sleep(READINESS_POLL_INTERVAL).await;
+    }
+}
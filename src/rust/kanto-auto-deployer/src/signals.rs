@@ -0,0 +1,129 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Handling of the process signals.
+//!
+//! SIGHUP asks the daemon to reload its configuration and redeploy the whole manifests directory.
+//! SIGTERM and SIGINT start a graceful shutdown: no new deployment is started and connection
+//! retries are given up, but the calls to Kanto-CM in flight are completed. A second SIGTERM or
+//! SIGINT exits right away, with the usual 128 + signal number status.
+use crate::systemd;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+/// Also used to raise signals from within, see [`reload`]
+static SENDER: Mutex<Option<UnboundedSender<DaemonSignal>>> = Mutex::new(None);
+
+/// Run when shutting down, e.g. to disconnect the MQTT clients
+type ShutdownHook = Box<dyn FnOnce() + Send>;
+static SHUTDOWN_HOOKS: Mutex<Vec<ShutdownHook>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DaemonSignal {
+    /// SIGHUP
+    Reload,
+    /// SIGTERM or SIGINT
+    Shutdown,
+}
+
+/// Receives the signals once the handlers are installed with [`listen`]
+pub struct Signals {
+    #[cfg_attr(not(feature = "filewatcher"), allow(dead_code))]
+    rx: UnboundedReceiver<DaemonSignal>,
+}
+
+impl Signals {
+    /// Waits for the next signal
+    #[cfg(feature = "filewatcher")]
+    pub async fn recv(&mut self) -> DaemonSignal {
+        match self.rx.recv().await {
+            Some(s) => s,
+            // The handlers are never removed, so this can not happen
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Whether a graceful shutdown is in progress
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Registers a hook to run on shutdown, see [`run_shutdown_hooks`]
+pub fn on_shutdown(hook: impl FnOnce() + Send + 'static) {
+    SHUTDOWN_HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+/// Asks the daemon to reload and redeploy as on SIGHUP, e.g. once it is enabled again
+#[cfg(feature = "mqtt")]
+pub fn reload() {
+    if let Some(tx) = SENDER.lock().unwrap().as_ref() {
+        // Only fails if the daemon is gone already
+        let _ = tx.send(DaemonSignal::Reload);
+    }
+}
+
+pub fn run_shutdown_hooks() {
+    let hooks = std::mem::take(&mut *SHUTDOWN_HOOKS.lock().unwrap());
+    for hook in hooks {
+        hook();
+    }
+}
+
+/// Replaces the default handlers of SIGHUP, SIGTERM and SIGINT (which kill the process).
+/// Has to be called from within the tokio runtime.
+pub fn listen() -> Result<Signals> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let (tx, rx) = unbounded_channel();
+    *SENDER.lock().unwrap() = Some(tx.clone());
+
+    tokio::spawn(async move {
+        loop {
+            let (signal, name, number) = tokio::select! {
+                _ = hangup.recv() => (DaemonSignal::Reload, "SIGHUP", libc_signal::SIGHUP),
+                _ = terminate.recv() => (DaemonSignal::Shutdown, "SIGTERM", libc_signal::SIGTERM),
+                _ = interrupt.recv() => (DaemonSignal::Shutdown, "SIGINT", libc_signal::SIGINT),
+            };
+            if signal == DaemonSignal::Shutdown {
+                if SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+                    log::warn!("Received {} again, exiting right away", name);
+                    std::process::exit(128 + number);
+                }
+                systemd::stopping();
+                log::info!(
+                    "Received {}, shutting down once the running deployments are done",
+                    name
+                );
+            } else {
+                log::info!("Received {}", name);
+            }
+            if tx.send(signal).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Signals { rx })
+}
+
+/// The signal numbers are the same on every Linux architecture KAD runs on
+mod libc_signal {
+    pub const SIGHUP: i32 = 1;
+    pub const SIGINT: i32 = 2;
+    pub const SIGTERM: i32 = 15;
+}
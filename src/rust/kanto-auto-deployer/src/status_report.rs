@@ -0,0 +1,281 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Machine-readable record of the last deployment of every manifest.
+//!
+//! The report is rewritten after each deployment, so that tools can tell why a container
+//! is missing without going through the logs. It is printed by the `status` subcommand.
+use crate::deploy_plan::{PlanAction, PlanFormat};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+pub static DEFAULT_STATUS_FILE: &str = "/var/lib/kanto-auto-deployer/status.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LastAction {
+    Created,
+    Recreated,
+    Started,
+    Unchanged,
+    /// The container was left as is, as it was not created by the auto-deployer
+    Skipped,
+    /// The container was removed, as its manifest was deleted or no longer defines it
+    Removed,
+    Failed,
+}
+
+impl From<PlanAction> for LastAction {
+    fn from(action: PlanAction) -> Self {
+        match action {
+            PlanAction::Create => LastAction::Created,
+            PlanAction::Recreate => LastAction::Recreated,
+            PlanAction::Start => LastAction::Started,
+            PlanAction::Unchanged => LastAction::Unchanged,
+            PlanAction::Skip => LastAction::Skipped,
+            PlanAction::Invalid => LastAction::Failed,
+            PlanAction::Prune => LastAction::Removed,
+        }
+    }
+}
+
+impl Display for LastAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str_repr = match self {
+            LastAction::Created => "created",
+            LastAction::Recreated => "recreated",
+            LastAction::Started => "started",
+            LastAction::Unchanged => "unchanged",
+            LastAction::Skipped => "skipped",
+            LastAction::Removed => "removed",
+            LastAction::Failed => "failed",
+        };
+        write!(f, "{}", str_repr)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContainerStatus {
+    pub name: String,
+    /// Kanto-CM id of the container, if it exists
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub action: LastAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestStatus {
+    /// SHA-256 of the manifest file content that was deployed, unset if it could not be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// RFC 3339 time of the last deployment
+    pub timestamp: String,
+    #[serde(default)]
+    pub containers: Vec<ContainerStatus>,
+    /// Error that prevented the manifest from being deployed at all (e.g. a syntax error)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// State of the lock that enables the auto-deployer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LockStatus {
+    pub path: PathBuf,
+    pub state: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StatusReport {
+    /// Only filled-in when printing the report, it is not part of the status file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<LockStatus>,
+    /// RFC 3339 time of the last change to the report
+    #[serde(default)]
+    pub updated: String,
+    #[serde(default)]
+    pub manifests: BTreeMap<PathBuf, ManifestStatus>,
+}
+
+impl Display for StatusReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(lock) = &self.lock {
+            writeln!(f, "Auto-deployer is {} ({:?})", lock.state, lock.path)?;
+        }
+        for (path, status) in &self.manifests {
+            write!(f, "{} ({}", path.display(), status.timestamp)?;
+            if let Some(hash) = &status.content_hash {
+                write!(f, ", sha256 {}", hash)?;
+            }
+            writeln!(f, ")")?;
+            if let Some(e) = &status.error {
+                writeln!(f, "    error: {}", e)?;
+            }
+            for c in &status.containers {
+                write!(f, "    {} [{}]", c.action, c.name)?;
+                if let Some(id) = &c.id {
+                    write!(f, " {}", id)?;
+                }
+                if let Some(e) = &c.error {
+                    write!(f, ": {}", e)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+pub(crate) fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// Gets notified about the manifests deployed (or removed) in one go, e.g. to publish them
+pub trait StatusListener: Send + Sync {
+    fn deployed(&self, manifests: &[(PathBuf, ManifestStatus)]);
+}
+
+/// The status report of a running instance, written to the status file after every change
+pub struct StatusStore {
+    path: PathBuf,
+    report: Mutex<StatusReport>,
+    /// Manifests recorded since the last save
+    pending: Mutex<Vec<(PathBuf, ManifestStatus)>>,
+    listeners: Vec<Arc<dyn StatusListener>>,
+}
+
+impl StatusStore {
+    /// Continues the report in the file, or starts an empty one if it does not exist (yet)
+    pub fn open(path: &Path) -> Self {
+        let report = match load(path) {
+            Ok(r) => r,
+            Err(e) => {
+                if path.exists() {
+                    log::warn!("Ignoring unreadable status file {:?}: {}", path, e);
+                }
+                StatusReport::default()
+            }
+        };
+        StatusStore {
+            path: path.to_path_buf(),
+            report: Mutex::new(report),
+            pending: Mutex::new(Vec::new()),
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn add_listener(&mut self, listener: Arc<dyn StatusListener>) {
+        self.listeners.push(listener);
+    }
+
+    pub fn record(
+        &self,
+        manifest: &Path,
+        content_hash: Option<String>,
+        containers: Vec<ContainerStatus>,
+        error: Option<String>,
+    ) {
+        let status = ManifestStatus {
+            content_hash,
+            timestamp: now(),
+            containers,
+            error,
+        };
+        self.pending
+            .lock()
+            .unwrap()
+            .push((manifest.to_path_buf(), status.clone()));
+        self.report
+            .lock()
+            .unwrap()
+            .manifests
+            .insert(manifest.to_path_buf(), status);
+    }
+
+    /// Marks the containers of a deleted manifest as removed
+    pub fn record_removed(&self, manifest: &Path, containers: Vec<ContainerStatus>) {
+        self.record(manifest, None, containers, None)
+    }
+
+    /// Forgets the manifests that are no longer part of the deployment
+    pub fn retain(&self, manifests: &[PathBuf]) {
+        self.report
+            .lock()
+            .unwrap()
+            .manifests
+            .retain(|p, _| manifests.contains(p));
+    }
+
+    /// Writes the report to the status file and notifies the listeners about the manifests
+    /// recorded since the last save. Failures are only logged, as the report must never
+    /// get in the way of the deployment itself.
+    pub fn save(&self) {
+        let recorded = std::mem::take(&mut *self.pending.lock().unwrap());
+        for listener in &self.listeners {
+            listener.deployed(&recorded);
+        }
+
+        let content = {
+            let mut report = self.report.lock().unwrap();
+            report.updated = now();
+            serde_json::to_string_pretty(&*report)
+        };
+        let result = content
+            .map_err(anyhow::Error::from)
+            .and_then(|c| write_atomically(&self.path, &c));
+        if let Err(e) = result {
+            log::warn!("Could not write status file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Replaces the file in one step, so that readers never see a partially written report
+pub(crate) fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<StatusReport> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn print_status(path: &Path, format: PlanFormat, lock: Option<LockStatus>) -> Result<()> {
+    let mut report =
+        load(path).map_err(|e| anyhow::anyhow!("Could not read status file {:?}: {}", path, e))?;
+    report.lock = lock;
+    match format {
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        PlanFormat::Text => {
+            print!("{}", report);
+            println!("Last updated {}", report.updated);
+        }
+    }
+    Ok(())
+}
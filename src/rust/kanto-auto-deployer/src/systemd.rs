@@ -0,0 +1,138 @@
+// ********************************************************************************
+// * Copyright (c) 2023 Contributors to the Eclipse Foundation
+// *
+// * See the NOTICE file(s) distributed with this work for additional
+// * information regarding copyright ownership.
+// *
+// * This program and the accompanying materials are made available under the
+// * terms of the Apache License 2.0 which is available at
+// * https://www.apache.org/licenses/LICENSE-2.0
+// *
+// * SPDX-License-Identifier: Apache-2.0
+// ********************************************************************************
+
+//! Notifications to systemd, for running as a `Type=notify` service.
+//!
+//! Nothing is sent unless `NOTIFY_SOCKET` is set, i.e. when not started by systemd. The watchdog
+//! is only pinged when systemd asks for it (`WatchdogSec=`, passed on as `WATCHDOG_USEC`).
+use crate::status_report::{LastAction, ManifestStatus, StatusListener};
+use sd_notify::NotifyState;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+#[cfg(feature = "filewatcher")]
+use std::time::Duration;
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        log::debug!("Could not notify systemd: {e}");
+    }
+}
+
+/// The initial deployment is done (or the configuration was reloaded)
+pub fn ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+#[cfg(feature = "filewatcher")]
+pub fn reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+pub fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+#[cfg(feature = "filewatcher")]
+pub fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// How often to ping the watchdog (half its timeout, as recommended by systemd), if it is enabled
+#[cfg(feature = "filewatcher")]
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+        Some(Duration::from_micros(usec / 2))
+    } else {
+        None
+    }
+}
+
+/// One line on the last deployment, e.g. "Deployed 3 manifest(s): 1 created, 2 unchanged"
+fn summary(manifests: &[(PathBuf, ManifestStatus)]) -> String {
+    let mut actions: BTreeMap<LastAction, usize> = BTreeMap::new();
+    for (_, status) in manifests {
+        if status.error.is_some() {
+            *actions.entry(LastAction::Failed).or_default() += 1;
+        }
+        for c in &status.containers {
+            *actions.entry(c.action).or_default() += 1;
+        }
+    }
+    let actions: Vec<String> = actions
+        .iter()
+        .map(|(action, count)| format!("{} {}", count, action))
+        .collect();
+    format!(
+        "Deployed {} manifest(s): {}",
+        manifests.len(),
+        actions.join(", ")
+    )
+}
+
+/// Reports the outcome of every deployment as the status of the service
+pub struct StatusNotifier;
+
+impl StatusListener for StatusNotifier {
+    fn deployed(&self, manifests: &[(PathBuf, ManifestStatus)]) {
+        status(&summary(manifests));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    /// The only test that sets `NOTIFY_SOCKET`, so that the others do not notify this socket
+    #[test]
+    fn notifications_are_sent_to_the_notify_socket() {
+        let path = std::env::temp_dir().join(format!("kad-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        let mut recv = || {
+            let len = socket.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).trim_end().to_string()
+        };
+        ready();
+        received.push(recv());
+        status("Running initial deployment");
+        received.push(recv());
+        #[cfg(feature = "filewatcher")]
+        {
+            watchdog();
+            received.push(recv());
+        }
+
+        std::env::remove_var("NOTIFY_SOCKET");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(received[0], "READY=1");
+        assert_eq!(received[1], "STATUS=Running initial deployment");
+        #[cfg(feature = "filewatcher")]
+        assert_eq!(received[2], "WATCHDOG=1");
+    }
+}